@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
 #[allow(dead_code)]
@@ -20,13 +20,25 @@ impl Address {
 
     #[allow(dead_code)]
     pub fn from_hex(hex_address: &str) -> Result<Address> {
-        let decoded = hex::decode(hex_address)?;
-        let mut address = [0u8; 32];
-        address.copy_from_slice(&decoded);
-        Ok(Address::new(address))
+        Ok(Address::new(decode_hex_32(hex_address)?))
     }
 }
 
+/// Decodes a 32 bytes hex string, optionally prefixed with `0x`
+pub fn decode_hex_32(hex_str: &str) -> Result<[u8; 32]> {
+    let hex_str = hex_str.trim();
+    let hex_str = hex_str.strip_prefix("0x").unwrap_or(hex_str);
+    if hex_str.is_empty() {
+        return Err(anyhow!("Empty hex string"));
+    }
+
+    let decoded = hex::decode(hex_str)?;
+    decoded
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("Invalid length: expected 32 bytes, got {}", decoded.len()))
+}
+
 impl AsRef<[u8]> for Address {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -38,3 +50,28 @@ impl From<[u8; 32]> for Address {
         Self(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_hex_strings_are_rejected() {
+        for input in ["", "  ", "0x", " 0x "] {
+            let error = decode_hex_32(input).unwrap_err();
+            assert_eq!(error.to_string(), "Empty hex string", "{:?}", input);
+
+            let error = Address::from_hex(input).unwrap_err();
+            assert_eq!(error.to_string(), "Empty hex string", "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn prefixed_and_padded_hex_strings_are_decoded() {
+        let hex = hex::encode([0xab; 32]);
+
+        for input in [hex.clone(), format!("0x{}", hex), format!(" {}\n", hex)] {
+            assert_eq!(Address::from_hex(&input).unwrap(), Address([0xab; 32]));
+        }
+    }
+}
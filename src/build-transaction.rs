@@ -1,13 +1,10 @@
-use address::Address;
 use anyhow::Result;
 use clap::Parser;
 use ed25519_dalek::Signer;
 use ed25519_dalek::SigningKey;
+use enokiweave::address::{decode_hex_32, Address};
+use enokiweave::transaction::Transaction;
 use serde_json::json;
-use transaction::Transaction;
-
-mod address;
-mod transaction;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -23,39 +20,27 @@ struct Args {
 
     #[arg(long)]
     recipient: String,
+
+    /// Id of the transaction this one refunds
+    #[arg(long)]
+    refund_of: Option<String>,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Convert hex private key to bytes
-    let private_key_bytes = hex::decode(args.private_key).expect("Invalid private key hex");
-    let private_key_array: [u8; 32] = private_key_bytes
-        .try_into()
-        .expect("Private key must be 32 bytes");
-
-    let signing_key = SigningKey::from_bytes(&private_key_array);
-
-    // Convert hex addresses to bytes
-    let sender_bytes = hex::decode(args.sender).expect("Invalid sender address hex");
-    let sender_array: [u8; 32] = sender_bytes
-        .try_into()
-        .expect("Sender address must be 32 bytes");
-
-    let recipient_bytes = hex::decode(args.recipient).expect("Invalid recipient address hex");
-    let recipient_array: [u8; 32] = recipient_bytes
-        .try_into()
-        .expect("Recipient address must be 32 bytes");
+    let signing_key = SigningKey::from_bytes(&decode_hex_32(&args.private_key)?);
 
     let tx = Transaction::new(
-        Address::from(sender_array),
-        Address::from(recipient_array),
+        Address::from_hex(&args.sender)?,
+        Address::from_hex(&args.recipient)?,
         args.amount,
     )?;
 
-    let signature = signing_key.sign(&tx.calculate_id()?);
+    let id = tx.calculate_id(args.refund_of.as_deref())?;
+    let signature = signing_key.sign(&id);
 
-    let json_output = json!({
+    let mut json_output = json!({
         "jsonrpc": "2.0",
         "method": "submitTransaction",
         "params": [{
@@ -68,10 +53,15 @@ fn main() -> Result<()> {
                 "s": hex::encode(signature.s_bytes())
             },
             "timestamp": tx.timestamp,
-            "id": hex::encode(tx.calculate_id()?)
-        }]
+            "id": hex::encode(id)
+        }],
+        "id": 1
     });
 
+    if let Some(refund_of) = args.refund_of {
+        json_output["params"][0]["refund_of"] = json!(refund_of);
+    }
+
     println!("{}", serde_json::to_string_pretty(&json_output)?);
 
     Ok(())
@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use ed25519_dalek::SigningKey;
+use enokiweave::address::decode_hex_32;
+use enokiweave::transaction_manager::{genesis_hash, TransactionManager, DB_NAME};
+use enokiweave::GenesisArgs;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Data directory of the node to snapshot
+    #[arg(long, default_value = "./local_db")]
+    data_dir: PathBuf,
+
+    /// Genesis file the node was started with
+    #[arg(long)]
+    genesis_file_path: String,
+
+    /// Key signing the snapshot, whose public key is given to the nodes loading it
+    #[arg(long)]
+    private_key: String,
+
+    /// Where to write the snapshot, printed when omitted
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let signing_key = SigningKey::from_bytes(&decode_hex_32(&args.private_key)?);
+
+    let genesis_args: GenesisArgs = serde_json::from_str(
+        &std::fs::read_to_string(&args.genesis_file_path)
+            .with_context(|| format!("Failed to read genesis file {}", args.genesis_file_path))?,
+    )
+    .with_context(|| format!("Failed to parse genesis file {}", args.genesis_file_path))?;
+
+    let transaction_manager = TransactionManager::open_read_only(&args.data_dir.join(DB_NAME))
+        .with_context(|| format!("Failed to open the database in {}", args.data_dir.display()))?;
+    let snapshot =
+        transaction_manager.create_snapshot(genesis_hash(&genesis_args)?, &signing_key)?;
+
+    let json_output = serde_json::to_string_pretty(&snapshot)?;
+    match args.output {
+        Some(output) => std::fs::write(&output, json_output)
+            .with_context(|| format!("Failed to write snapshot to {}", output.display()))?,
+        None => println!("{}", json_output),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,310 @@
+use libp2p::core::{ConnectedPoint, Endpoint};
+use libp2p::swarm::{
+    dummy, CloseConnection, ConnectionClosed, ConnectionDenied, ConnectionId, FromSwarm,
+    NetworkBehaviour, PollParameters, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::task::{Context, Poll};
+use tracing::trace;
+use void::Void;
+
+/// Limits the number of connections per peer, resolving simultaneous dials the
+/// same way on both ends.
+///
+/// When two nodes dial each other at the same time, each may establish the
+/// connections in a different order. Keeping whichever came first could make
+/// each node drop the connection the other one kept. Instead, once the limit is
+/// reached, the connection dialed by the lower peer id is preferred: it replaces
+/// an established connection dialed by the higher one, and is never replaced.
+pub struct DuplicateConnections {
+    local_peer_id: PeerId,
+    max_per_peer: usize,
+    // Established connections of each peer, with whether they are preferred
+    established: HashMap<PeerId, HashMap<ConnectionId, bool>>,
+    pending_closes: VecDeque<(PeerId, ConnectionId)>,
+}
+
+#[derive(Debug)]
+struct DuplicateConnection {
+    peer_id: PeerId,
+}
+
+impl fmt::Display for DuplicateConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "already connected to {}", self.peer_id)
+    }
+}
+
+impl std::error::Error for DuplicateConnection {}
+
+impl DuplicateConnections {
+    pub fn new(local_peer_id: PeerId, max_per_peer: u32) -> Self {
+        Self {
+            local_peer_id,
+            max_per_peer: max_per_peer.max(1) as usize,
+            established: HashMap::new(),
+            pending_closes: VecDeque::new(),
+        }
+    }
+
+    /// Whether a connection with `peer` was dialed by the lower peer id
+    fn is_preferred(&self, peer: PeerId, dialed_locally: bool) -> bool {
+        (self.local_peer_id < peer) == dialed_locally
+    }
+
+    /// Established connection of `peer` that a preferred one replaces, once the
+    /// limit is reached
+    fn replaceable(&self, peer: PeerId) -> Option<ConnectionId> {
+        self.established
+            .get(&peer)?
+            .iter()
+            .find(|(_, preferred)| !**preferred)
+            .map(|(connection_id, _)| *connection_id)
+    }
+
+    /// Accepts a new connection with `peer` if it is under the limit, or if it
+    /// is preferred over an established one. Nothing is closed yet, as other
+    /// behaviours may still deny the new connection.
+    fn admit(&self, peer: PeerId, dialed_locally: bool) -> Result<(), ConnectionDenied> {
+        let Some(connections) = self.established.get(&peer) else {
+            return Ok(());
+        };
+        if connections.len() < self.max_per_peer {
+            return Ok(());
+        }
+
+        if self.is_preferred(peer, dialed_locally) && self.replaceable(peer).is_some() {
+            return Ok(());
+        }
+        Err(ConnectionDenied::new(DuplicateConnection { peer_id: peer }))
+    }
+
+    /// Counts a connection every behaviour accepted, closing the connection it
+    /// replaces. Should another connection have taken the last place since it
+    /// was admitted, the new connection is closed instead.
+    fn on_established(&mut self, peer: PeerId, connection_id: ConnectionId, dialed_locally: bool) {
+        let preferred = self.is_preferred(peer, dialed_locally);
+        let at_limit = self
+            .established
+            .get(&peer)
+            .is_some_and(|connections| connections.len() >= self.max_per_peer);
+        if at_limit {
+            let closed = match self.replaceable(peer) {
+                Some(replaced) if preferred => {
+                    trace!("Replacing connection {:?} with {}", replaced, peer);
+                    // No longer counted, so it is replaced only once
+                    self.on_closed(peer, replaced);
+                    replaced
+                }
+                _ => connection_id,
+            };
+            self.pending_closes.push_back((peer, closed));
+            if closed == connection_id {
+                return;
+            }
+        }
+        self.established
+            .entry(peer)
+            .or_default()
+            .insert(connection_id, preferred);
+    }
+
+    fn on_closed(&mut self, peer: PeerId, connection_id: ConnectionId) {
+        if let Some(connections) = self.established.get_mut(&peer) {
+            connections.remove(&connection_id);
+            if connections.is_empty() {
+                self.established.remove(&peer);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviour for DuplicateConnections {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type OutEvent = Void;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.admit(peer, false)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.admit(peer, true)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match event {
+            FromSwarm::ConnectionEstablished(established) => self.on_established(
+                established.peer_id,
+                established.connection_id,
+                matches!(established.endpoint, ConnectedPoint::Dialer { .. }),
+            ),
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                ..
+            }) => self.on_closed(peer_id, connection_id),
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        void::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::OutEvent, THandlerInEvent<Self>>> {
+        match self.pending_closes.pop_front() {
+            Some((peer_id, connection_id)) => Poll::Ready(ToSwarm::CloseConnection {
+                peer_id,
+                connection: CloseConnection::One(connection_id),
+            }),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Establishes a connection on one end, as the swarm would
+    fn connect(
+        node: &mut DuplicateConnections,
+        peer: PeerId,
+        connection_id: ConnectionId,
+        dialed_locally: bool,
+    ) -> bool {
+        if node.admit(peer, dialed_locally).is_err() {
+            return false;
+        }
+        node.on_established(peer, connection_id, dialed_locally);
+        true
+    }
+
+    fn kept(node: &mut DuplicateConnections, peer: PeerId) -> Vec<ConnectionId> {
+        while let Some((_, connection_id)) = node.pending_closes.pop_front() {
+            node.on_closed(peer, connection_id);
+        }
+        node.established[&peer].keys().copied().collect()
+    }
+
+    #[test]
+    fn simultaneous_dials_keep_the_same_connection_on_both_ends() {
+        let (a, b) = {
+            let (x, y) = (PeerId::random(), PeerId::random());
+            if x < y {
+                (x, y)
+            } else {
+                (y, x)
+            }
+        };
+        // Dialed by a, the lower peer id, and by b
+        let dialed_by_a = ConnectionId::new_unchecked(1);
+        let dialed_by_b = ConnectionId::new_unchecked(2);
+
+        // a sees its own dial complete first, b sees its own dial complete first
+        let mut node_a = DuplicateConnections::new(a, 1);
+        assert!(connect(&mut node_a, b, dialed_by_a, true));
+        assert!(!connect(&mut node_a, b, dialed_by_b, false));
+
+        let mut node_b = DuplicateConnections::new(b, 1);
+        assert!(connect(&mut node_b, a, dialed_by_b, true));
+        assert!(connect(&mut node_b, a, dialed_by_a, false));
+
+        assert_eq!(kept(&mut node_a, b), vec![dialed_by_a]);
+        assert_eq!(kept(&mut node_b, a), vec![dialed_by_a]);
+    }
+
+    #[test]
+    fn connections_under_the_limit_are_accepted() {
+        let (local, peer) = (PeerId::random(), PeerId::random());
+        let mut node = DuplicateConnections::new(local, 2);
+
+        assert!(connect(
+            &mut node,
+            peer,
+            ConnectionId::new_unchecked(1),
+            true
+        ));
+        assert!(connect(
+            &mut node,
+            peer,
+            ConnectionId::new_unchecked(2),
+            false
+        ));
+        assert_eq!(kept(&mut node, peer).len(), 2);
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_denied() {
+        let (local, peer) = (PeerId::random(), PeerId::random());
+        let mut node = DuplicateConnections::new(local, 2);
+        // Both preferred, so neither can be replaced
+        let dialed_locally = local < peer;
+        for id in 1..=2 {
+            assert!(connect(
+                &mut node,
+                peer,
+                ConnectionId::new_unchecked(id),
+                dialed_locally
+            ));
+        }
+
+        for dialed_locally in [true, false] {
+            assert!(!connect(
+                &mut node,
+                peer,
+                ConnectionId::new_unchecked(3),
+                dialed_locally
+            ));
+        }
+        assert_eq!(kept(&mut node, peer).len(), 2);
+    }
+
+    #[test]
+    fn connection_is_replaced_only_once_established() {
+        let (a, b) = {
+            let (x, y) = (PeerId::random(), PeerId::random());
+            if x < y {
+                (x, y)
+            } else {
+                (y, x)
+            }
+        };
+        let dialed_by_b = ConnectionId::new_unchecked(1);
+        let mut node_a = DuplicateConnections::new(a, 1);
+        assert!(connect(&mut node_a, b, dialed_by_b, false));
+
+        // Admitted, then denied by another behaviour, e.g. a blocked peer
+        assert!(node_a.admit(b, true).is_ok());
+        assert!(node_a.pending_closes.is_empty());
+
+        let dialed_by_a = ConnectionId::new_unchecked(2);
+        node_a.on_established(b, dialed_by_a, true);
+        assert_eq!(kept(&mut node_a, b), vec![dialed_by_a]);
+    }
+}
@@ -0,0 +1,99 @@
+pub mod address;
+mod duplicate_connections;
+mod network_status;
+mod node;
+mod peer_dialer;
+mod peer_store;
+mod rpc;
+pub mod transaction;
+pub mod transaction_manager;
+
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+pub use network_status::PeerInfo;
+pub use node::{run_node, NodeConfig, RunningNode};
+
+#[derive(Deserialize)]
+pub struct GenesisArgs {
+    #[serde(deserialize_with = "deserialize_unique_balances")]
+    balances: HashMap<String, u64>,
+}
+
+// serde_json silently keeps the last value of a duplicated key, which would hide
+// an address funded twice by mistake
+fn deserialize_unique_balances<'de, D>(deserializer: D) -> Result<HashMap<String, u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct UniqueBalancesVisitor;
+
+    impl<'de> Visitor<'de> for UniqueBalancesVisitor {
+        type Value = HashMap<String, u64>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map of addresses to balances")
+        }
+
+        fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
+        where
+            M: MapAccess<'de>,
+        {
+            let mut balances = HashMap::new();
+            let mut seen = HashSet::new();
+            while let Some((address, amount)) = map.next_entry::<String, u64>()? {
+                let normalized = address.trim().trim_start_matches("0x").to_lowercase();
+                if !seen.insert(normalized) {
+                    return Err(de::Error::custom(format!(
+                        "duplicate address in genesis: {}",
+                        address
+                    )));
+                }
+                balances.insert(address, amount);
+            }
+            Ok(balances)
+        }
+    }
+
+    deserializer.deserialize_map(UniqueBalancesVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_genesis(content: &str) -> serde_json::Result<GenesisArgs> {
+        serde_json::from_str(content)
+    }
+
+    #[test]
+    fn duplicate_genesis_address_is_rejected() {
+        let address = hex::encode([1; 32]);
+
+        for duplicate in [
+            address.clone(),
+            format!("0x{}", address),
+            address.to_uppercase(),
+        ] {
+            let content = format!(
+                r#"{{"balances": {{"{}": 10, "{}": 20}}}}"#,
+                address, duplicate
+            );
+            let error = parse_genesis(&content).err().unwrap().to_string();
+            assert!(error.contains("duplicate address in genesis"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn distinct_genesis_addresses_are_kept() {
+        let content = format!(
+            r#"{{"balances": {{"{}": 10, "{}": 20}}}}"#,
+            hex::encode([1; 32]),
+            hex::encode([2; 32])
+        );
+
+        assert_eq!(parse_genesis(&content).unwrap().balances.len(), 2);
+    }
+}
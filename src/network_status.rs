@@ -0,0 +1,161 @@
+use libp2p::{Multiaddr, PeerId};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{error, info, trace};
+
+/// Connectivity of the node, updated by the swarm event loop and read by the
+/// RPC health check
+pub struct NetworkStatus {
+    connected_peers: AtomicUsize,
+    isolated: AtomicBool,
+    // Time since which the node has had no connected peer
+    disconnected_since: Mutex<Instant>,
+    // None disables isolation detection, e.g. for a single node network
+    isolation_threshold: Option<Duration>,
+}
+
+impl NetworkStatus {
+    pub fn new(isolation_threshold: Option<Duration>) -> Self {
+        Self {
+            connected_peers: AtomicUsize::new(0),
+            isolated: AtomicBool::new(false),
+            disconnected_since: Mutex::new(Instant::now()),
+            isolation_threshold,
+        }
+    }
+
+    pub fn peer_connected(&self) {
+        self.connected_peers.fetch_add(1, Ordering::SeqCst);
+        if self.isolated.swap(false, Ordering::SeqCst) {
+            info!("Node is connected to the network again");
+        }
+    }
+
+    pub fn peer_disconnected(&self) {
+        let previous = self
+            .connected_peers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                Some(count.saturating_sub(1))
+            })
+            .unwrap_or(0);
+        if previous <= 1 {
+            *self
+                .disconnected_since
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        }
+    }
+
+    /// Flags the node as isolated once it has had no connected peer for longer
+    /// than the isolation threshold
+    pub fn check_isolation(&self) {
+        let connected_peers = self.connected_peers();
+        trace!("Connected peers: {}", connected_peers);
+
+        let Some(isolation_threshold) = self.isolation_threshold else {
+            return;
+        };
+        if connected_peers > 0 || self.is_isolated() {
+            return;
+        }
+
+        let disconnected_for = self
+            .disconnected_since
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .elapsed();
+        if disconnected_for >= isolation_threshold {
+            error!(
+                "Node has had no connected peer for {:?}, it is isolated from the network",
+                disconnected_for
+            );
+            self.isolated.store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub fn connected_peers(&self) -> usize {
+        self.connected_peers.load(Ordering::SeqCst)
+    }
+
+    pub fn is_isolated(&self) -> bool {
+        self.isolated.load(Ordering::SeqCst)
+    }
+}
+
+/// A peer the node is connected to or has discovered, as reported to operators
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub address: String,
+    pub connected: bool,
+    /// `inbound` or `outbound` while connected
+    pub direction: Option<&'static str>,
+    /// Unix timestamp in milliseconds of the last connection event or ping
+    /// with the peer, if the node ever was connected to it
+    pub last_seen: Option<i64>,
+    /// Round trip time of the last ping, while connected
+    pub latency_ms: Option<u64>,
+}
+
+impl PeerInfo {
+    /// A peer the node knows an address of but hasn't connected to yet
+    pub(crate) fn discovered(peer_id: PeerId, address: &Multiaddr) -> Self {
+        Self {
+            peer_id: peer_id.to_string(),
+            address: address.to_string(),
+            connected: false,
+            direction: None,
+            last_seen: None,
+            latency_ms: None,
+        }
+    }
+}
+
+/// Operator commands sent by the RPC server to the swarm event loop, which owns
+/// the swarm
+pub enum NetworkCommand {
+    /// Replies with the connected peers and the peers discovered since startup
+    Peers(oneshot::Sender<Vec<PeerInfo>>),
+    /// Disconnects the peer and bans it for the configured duration. Replies
+    /// whether the peer was connected.
+    DisconnectPeer(PeerId, oneshot::Sender<bool>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_without_peers_past_the_threshold_is_isolated() {
+        let status = NetworkStatus::new(Some(Duration::ZERO));
+        status.check_isolation();
+        assert!(status.is_isolated());
+
+        status.peer_connected();
+        assert!(!status.is_isolated());
+        status.check_isolation();
+        assert!(!status.is_isolated());
+
+        status.peer_disconnected();
+        status.check_isolation();
+        assert!(status.is_isolated());
+    }
+
+    #[test]
+    fn node_is_not_isolated_before_the_threshold() {
+        let status = NetworkStatus::new(Some(Duration::from_secs(3600)));
+        status.check_isolation();
+        assert!(!status.is_isolated());
+    }
+
+    #[test]
+    fn isolation_detection_can_be_disabled() {
+        let status = NetworkStatus::new(None);
+        status.check_isolation();
+        assert!(!status.is_isolated());
+        assert_eq!(status.connected_peers(), 0);
+    }
+}
@@ -0,0 +1,1097 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use clap::Parser;
+use ed25519_dalek::VerifyingKey;
+use libp2p::futures::StreamExt;
+use libp2p::mdns::tokio::Tokio;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::NetworkBehaviour;
+use libp2p::{
+    allow_block_list::{self, BlockedPeers},
+    connection_limits::{self, ConnectionLimits},
+    core::{muxing::StreamMuxerBox, transport::Boxed, upgrade::Version, ConnectedPoint},
+    identity, noise, ping, tcp, yamux, PeerId, Swarm, Transport,
+};
+use libp2p::{
+    floodsub::{Floodsub, FloodsubEvent, Topic},
+    mdns::{Behaviour as Mdns, Event as MdnsEvent},
+    swarm::{DialError, ListenError, SwarmBuilder, SwarmEvent},
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tcp::tokio::Transport as TokioTransport;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{error, info, trace, warn};
+
+use crate::address::decode_hex_32;
+use crate::duplicate_connections::DuplicateConnections;
+use crate::network_status::{NetworkCommand, NetworkStatus, PeerInfo};
+use crate::peer_dialer::{expected_peer_id, parse_initial_peer, InitialPeerDialer};
+use crate::peer_store::PeerStore;
+use crate::rpc::{run_http_rpc_server, RpcConfig};
+use crate::transaction_manager::{
+    genesis_hash, Snapshot, TransactionManager, TransactionManagerRegistry, DB_NAME, DEFAULT_LEDGER,
+};
+use crate::GenesisArgs;
+
+// Locations inside the data directory
+const LEDGERS_DIR: &str = "ledgers";
+const PEER_STORE_PATH: &str = "peers.txt";
+const NODE_KEY_PATH: &str = "node_key";
+const LISTEN_PORT_PATH: &str = "listen_port";
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "OutEvent")]
+struct P2PBlockchainBehaviour {
+    // First, so the connections it denies are never counted by the others
+    duplicate_connections: DuplicateConnections,
+    connection_limits: connection_limits::Behaviour,
+    blocked_peers: allow_block_list::Behaviour<BlockedPeers>,
+    floodsub: Floodsub,
+    mdns: Toggle<Mdns<Tokio>>,
+    ping: ping::Behaviour,
+}
+
+impl From<void::Void> for OutEvent {
+    fn from(value: void::Void) -> Self {
+        void::unreachable(value)
+    }
+}
+
+impl From<FloodsubEvent> for OutEvent {
+    fn from(value: FloodsubEvent) -> Self {
+        OutEvent::Floodsub(value)
+    }
+}
+impl From<MdnsEvent> for OutEvent {
+    fn from(value: MdnsEvent) -> Self {
+        OutEvent::Mdns(Box::new(value))
+    }
+}
+impl From<ping::Event> for OutEvent {
+    fn from(value: ping::Event) -> Self {
+        OutEvent::Ping(value)
+    }
+}
+
+enum OutEvent {
+    Floodsub(FloodsubEvent),
+    Mdns(Box<MdnsEvent>),
+    Ping(ping::Event),
+}
+
+/// Configuration of a node, parsed from the command line by the binary
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+pub struct NodeConfig {
+    #[arg(long)]
+    pub genesis_file_path: String,
+    /// Directory of the databases and of the peer store
+    #[arg(long, default_value = "./local_db")]
+    pub data_dir: PathBuf,
+    /// Bootstrap from a signed snapshot instead of the genesis balances
+    #[arg(long, requires = "snapshot_public_key")]
+    pub snapshot_file_path: Option<String>,
+    /// Hex encoded public key of the node trusted to sign the snapshot
+    #[arg(long)]
+    pub snapshot_public_key: Option<String>,
+    /// Additional independent ledger, as `<name>=<genesis file path>`. RPC
+    /// requests select it with their `ledger` field.
+    #[arg(long = "ledger")]
+    pub ledgers: Vec<String>,
+    /// File with one initial peer per line, as `<multiaddr> [priority]`
+    #[arg(long)]
+    pub initial_peers_file_path: Option<String>,
+    /// Port peers connect to. Defaults to the port of the previous run, so the
+    /// addresses other peers stored stay valid, and to a port assigned by the OS
+    /// on the first run.
+    #[arg(long)]
+    pub p2p_port: Option<u16>,
+    /// Don't discover peers on the local network with mDNS
+    #[arg(long)]
+    pub disable_mdns: bool,
+    /// Initial peers, as `<multiaddr> [priority]`. Higher priorities are dialed
+    /// first, and lower ones only once those have failed.
+    #[arg(long)]
+    pub initial_peers: Option<Vec<String>>,
+    /// Maximum number of initial peers dialed at the same time
+    #[arg(long, default_value = "4")]
+    pub initial_dial_fanout: usize,
+    /// Number of times each initial peer is dialed before giving up
+    #[arg(long, default_value = "5")]
+    pub initial_dial_attempts: u32,
+    #[arg(long, default_value = "3001")]
+    pub rpc_port: u16,
+    /// Also serve the RPC over a unix domain socket at this path
+    #[arg(long)]
+    pub rpc_unix_socket: Option<PathBuf>,
+    /// Minimum RPC response size, in bytes, before gzip is applied for clients accepting it
+    #[arg(long, default_value = "1024")]
+    pub rpc_gzip_threshold: usize,
+    /// Maximum size, in bytes, of an RPC request with its headers
+    #[arg(long, default_value = "1048576")]
+    pub rpc_max_request_size: usize,
+    /// Seconds to wait once the transaction queue is empty before a draining node exits
+    #[arg(long, default_value = "10")]
+    pub drain_grace_period_secs: u64,
+    /// Number of workers processing queued transactions concurrently
+    #[arg(long, default_value = "1")]
+    pub transaction_workers: usize,
+    /// Maximum number of established connections with a single peer
+    #[arg(long, default_value = "1")]
+    pub max_connections_per_peer: u32,
+    /// Maximum number of established connections across all peers
+    #[arg(long)]
+    pub max_established_total: Option<u32>,
+    /// Seconds without any connected peer after which the node reports itself
+    /// as isolated on `/health`. Disabled when not set, e.g. for a single node.
+    #[arg(long)]
+    pub isolation_threshold_secs: Option<u64>,
+    /// Number of most recent records kept in each selfchain. Older records are
+    /// pruned and folded into a checkpoint of the balance. Keeps everything when
+    /// not set.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub retain_transactions: Option<u32>,
+    /// Seconds between two pruning passes
+    #[arg(long, default_value = "3600")]
+    pub prune_interval_secs: u64,
+    /// Seconds a peer disconnected with `disconnectPeer` is refused
+    #[arg(long, default_value = "600")]
+    pub peer_ban_secs: u64,
+    /// Token required by the admin RPC methods. They are refused when not set.
+    #[arg(long)]
+    pub rpc_admin_token: Option<String>,
+}
+
+async fn handle_swarm_events(
+    mut swarm: Swarm<P2PBlockchainBehaviour>,
+    mut initial_peer_dialer: InitialPeerDialer,
+    peer_store: Arc<std::sync::Mutex<PeerStore>>,
+    listen_port_path: PathBuf,
+    network_status: Arc<NetworkStatus>,
+    mut network_commands: mpsc::Receiver<NetworkCommand>,
+    peer_ban_duration: Duration,
+) {
+    // Connected and discovered peers
+    let mut peers: HashMap<PeerId, PeerInfo> = HashMap::new();
+    // Peers disconnected by an operator, with the end of their ban
+    let mut banned_peers: HashMap<PeerId, Instant> = HashMap::new();
+    let mut dial_interval = tokio::time::interval(Duration::from_millis(500));
+    let mut health_interval = tokio::time::interval(Duration::from_secs(5));
+
+    loop {
+        let event = tokio::select! {
+            _ = dial_interval.tick() => {
+                initial_peer_dialer.dial_due(&mut swarm);
+                forget_given_up_peers(&mut initial_peer_dialer, &peer_store);
+                continue;
+            }
+            _ = health_interval.tick() => {
+                network_status.check_isolation();
+                banned_peers.retain(|peer_id, banned_until| {
+                    if *banned_until > Instant::now() {
+                        return true;
+                    }
+                    info!("Ban of peer {} expired", peer_id);
+                    swarm.behaviour_mut().blocked_peers.unblock_peer(*peer_id);
+                    swarm
+                        .behaviour_mut()
+                        .floodsub
+                        .add_node_to_partial_view(*peer_id);
+                    false
+                });
+                continue;
+            }
+            Some(command) = network_commands.recv() => {
+                match command {
+                    NetworkCommand::Peers(reply) => {
+                        let _ = reply.send(peers.values().cloned().collect());
+                    }
+                    NetworkCommand::DisconnectPeer(peer_id, reply) => {
+                        warn!("Disconnecting and banning peer {} for {:?}", peer_id, peer_ban_duration);
+                        banned_peers.insert(peer_id, Instant::now() + peer_ban_duration);
+                        // Blocking closes the open connections and denies new ones.
+                        // Floodsub would otherwise keep redialing the peer.
+                        swarm.behaviour_mut().blocked_peers.block_peer(peer_id);
+                        swarm
+                            .behaviour_mut()
+                            .floodsub
+                            .remove_node_from_partial_view(&peer_id);
+                        let _ = reply.send(peers.get(&peer_id).is_some_and(|peer| peer.connected));
+                    }
+                }
+                continue;
+            }
+            event = swarm.select_next_some() => event,
+        };
+
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Listening on {:?}", address);
+                if let Some(Protocol::Tcp(port)) = address.iter().last() {
+                    if let Err(e) = std::fs::write(&listen_port_path, port.to_string()) {
+                        warn!("Failed to save the listen port: {}", e);
+                    }
+                }
+                // Initial peers are only dialed once the node can accept their
+                // connections back
+                initial_peer_dialer.start();
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                if num_established.get() == 1 {
+                    network_status.peer_connected();
+                }
+
+                let peer = peers.entry(peer_id).or_insert_with(|| {
+                    PeerInfo::discovered(peer_id, endpoint.get_remote_address())
+                });
+                if !peer.connected {
+                    peer.address = endpoint.get_remote_address().to_string();
+                    peer.connected = true;
+                    peer.direction = Some(if endpoint.is_dialer() {
+                        "outbound"
+                    } else {
+                        "inbound"
+                    });
+                }
+                peer.last_seen = Some(Utc::now().timestamp_millis());
+
+                initial_peer_dialer.on_peer_connected(peer_id);
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    initial_peer_dialer.on_connected(address);
+                    peer_store
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(peer_id, address.clone());
+                }
+
+                info!(
+                    "Connection established with {} ({})",
+                    peer_id,
+                    if endpoint.is_dialer() {
+                        "outbound"
+                    } else {
+                        "inbound"
+                    }
+                );
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                network_status.peer_disconnected();
+                if let Some(peer) = peers.get_mut(&peer_id) {
+                    peer.connected = false;
+                    peer.direction = None;
+                    peer.last_seen = Some(Utc::now().timestamp_millis());
+                    peer.latency_ms = None;
+                }
+                info!("Disconnected from {}", peer_id);
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id,
+                error: DialError::WrongPeerId { obtained, endpoint },
+            } => {
+                warn!(
+                    "Peer at {} authenticated as {} but {:?} was expected, connection rejected",
+                    endpoint.get_remote_address(),
+                    obtained,
+                    peer_id
+                );
+                // The peer at this address has another identity now, likely
+                // since its restart, so dialing it again can't succeed
+                initial_peer_dialer.give_up(endpoint.get_remote_address(), "wrong peer id");
+            }
+            SwarmEvent::OutgoingConnectionError {
+                error: DialError::Transport(errors),
+                ..
+            } => {
+                for (address, e) in errors {
+                    trace!("Failed to dial {}: {}", address, e);
+                    initial_peer_dialer.on_dial_failed(&address, &format!("{:?}", e));
+                }
+            }
+            // Connections over the configured limits are denied. When two nodes
+            // dial each other at the same time, both keep the connection dialed by
+            // the lower peer id, see DuplicateConnections.
+            SwarmEvent::OutgoingConnectionError {
+                peer_id,
+                error: DialError::Denied { cause },
+            } => {
+                trace!("Denied outbound connection to {:?}: {}", peer_id, cause);
+                if let Some(peer_id) = peer_id {
+                    initial_peer_dialer.on_peer_dial_failed(peer_id, &cause.to_string());
+                }
+            }
+            // Other dial errors don't report the dialed address. Dials to peers
+            // without a known peer id are eventually timed out by the dialer.
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+            } => {
+                trace!("Failed to dial {}: {}", peer_id, error);
+                initial_peer_dialer.on_peer_dial_failed(peer_id, &error.to_string());
+            }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error: ListenError::Denied { cause },
+                ..
+            } => {
+                trace!(
+                    "Denied inbound connection from {}: {}",
+                    send_back_addr,
+                    cause
+                );
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(FloodsubEvent::Message(_))) => {}
+            SwarmEvent::Behaviour(OutEvent::Ping(ping::Event {
+                peer,
+                result: Ok(ping::Success::Ping { rtt }),
+            })) => {
+                if let Some(peer) = peers.get_mut(&peer) {
+                    peer.last_seen = Some(Utc::now().timestamp_millis());
+                    peer.latency_ms = Some(rtt.as_millis() as u64);
+                }
+            }
+            SwarmEvent::Behaviour(OutEvent::Mdns(mdns_event)) => match *mdns_event {
+                MdnsEvent::Discovered(list) => {
+                    for (peer_id, multiaddr) in list {
+                        if banned_peers.contains_key(&peer_id) {
+                            continue;
+                        }
+                        peers
+                            .entry(peer_id)
+                            .or_insert_with(|| PeerInfo::discovered(peer_id, &multiaddr));
+                        peer_store
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .insert(peer_id, multiaddr);
+                        swarm
+                            .behaviour_mut()
+                            .floodsub
+                            .add_node_to_partial_view(peer_id);
+                    }
+                }
+                MdnsEvent::Expired(list) => {
+                    for (peer_id, _multiaddr) in list {
+                        swarm
+                            .behaviour_mut()
+                            .floodsub
+                            .remove_node_from_partial_view(&peer_id);
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        forget_given_up_peers(&mut initial_peer_dialer, &peer_store);
+    }
+}
+
+/// Removes the peers the dialer gave up on from the peer store, so they aren't
+/// dialed again on every restart
+fn forget_given_up_peers(
+    initial_peer_dialer: &mut InitialPeerDialer,
+    peer_store: &std::sync::Mutex<PeerStore>,
+) {
+    for address in initial_peer_dialer.take_given_up() {
+        if let Some(peer_id) = expected_peer_id(&address) {
+            peer_store
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(peer_id, &address);
+        }
+    }
+}
+
+/// Handle of a node started with [`run_node`]
+pub struct RunningNode {
+    local_peer_id: PeerId,
+    shutdown_sender: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+    network_commands: mpsc::Sender<NetworkCommand>,
+}
+
+impl RunningNode {
+    /// Peer id of the node, derived from the key kept in its data directory
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Connected peers and peers discovered since the node started
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>> {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        self.network_commands
+            .send(NetworkCommand::Peers(reply_sender))
+            .await
+            .map_err(|_| anyhow!("Node networking has stopped"))?;
+        reply_receiver
+            .await
+            .map_err(|_| anyhow!("Node networking has stopped"))
+    }
+
+    /// Stops the RPC server and the networking, then flushes the peer store and
+    /// the databases
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown_sender.send(());
+        self.task.await.context("Node task panicked")?
+    }
+
+    /// Runs until the node stops by itself, e.g. once drained, or until `signal`
+    /// resolves, in which case the node is shut down
+    pub async fn run_until(mut self, signal: impl Future<Output = ()>) -> Result<()> {
+        tokio::select! {
+            result = &mut self.task => result.context("Node task panicked")?,
+            _ = signal => {
+                info!("Shutting down");
+                self.shutdown().await
+            }
+        }
+    }
+}
+
+/// Opens the ledgers and starts the peer-to-peer networking and the RPC server.
+/// The node runs in background tasks until [`RunningNode::shutdown`] is called.
+pub async fn run_node(config: NodeConfig) -> Result<RunningNode> {
+    let mut ledgers = TransactionManagerRegistry::new();
+    let transaction_manager = ledgers.insert(
+        DEFAULT_LEDGER,
+        TransactionManager::new(&config.data_dir.join(DB_NAME)).with_context(|| {
+            format!(
+                "Failed to open the database in {}, is the directory writable?",
+                config.data_dir.join(DB_NAME).display()
+            )
+        })?,
+    )?;
+
+    {
+        let genesis_content = std::fs::read_to_string(&config.genesis_file_path)
+            .with_context(|| format!("Failed to read genesis file {}", config.genesis_file_path))?;
+        let genesis_args: GenesisArgs =
+            serde_json::from_str(&genesis_content).with_context(|| {
+                format!("Failed to parse genesis file {}", config.genesis_file_path)
+            })?;
+
+        match (&config.snapshot_file_path, &config.snapshot_public_key) {
+            (Some(snapshot_file_path), Some(snapshot_public_key)) => {
+                let snapshot: Snapshot = serde_json::from_str(
+                    &std::fs::read_to_string(snapshot_file_path).with_context(|| {
+                        format!("Failed to read snapshot file {}", snapshot_file_path)
+                    })?,
+                )
+                .with_context(|| format!("Failed to parse snapshot file {}", snapshot_file_path))?;
+                let public_key_bytes =
+                    decode_hex_32(snapshot_public_key).context("Invalid snapshot public key")?;
+
+                transaction_manager.load_snapshot(
+                    snapshot,
+                    genesis_hash(&genesis_args)?,
+                    VerifyingKey::from_bytes(&public_key_bytes)
+                        .context("Invalid snapshot public key")?,
+                )?;
+            }
+            _ => transaction_manager.load_genesis_transactions(genesis_args)?,
+        }
+    }
+
+    for ledger in &config.ledgers {
+        let (name, genesis_file_path) = ledger
+            .split_once('=')
+            .context("Ledgers must be given as <name>=<genesis file path>")?;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Invalid ledger name: {}", name);
+        }
+
+        let genesis_args: GenesisArgs = serde_json::from_str(
+            &std::fs::read_to_string(genesis_file_path).with_context(|| {
+                format!(
+                    "Failed to read genesis file {} of ledger {}",
+                    genesis_file_path, name
+                )
+            })?,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to parse genesis file {} of ledger {}",
+                genesis_file_path, name
+            )
+        })?;
+        let ledger_path = config.data_dir.join(LEDGERS_DIR).join(name);
+        let transaction_manager = ledgers.insert(
+            name,
+            TransactionManager::new(&ledger_path).with_context(|| {
+                format!(
+                    "Failed to open the database of ledger {} in {}, is the directory writable?",
+                    name,
+                    ledger_path.display()
+                )
+            })?,
+        )?;
+        transaction_manager.load_genesis_transactions(genesis_args)?;
+        info!("Loaded ledger {}", name);
+    }
+
+    // Kept across restarts, so the addresses other peers stored stay valid
+    let local_key = load_or_create_node_key(&config.data_dir.join(NODE_KEY_PATH))?;
+    let local_peer_id = PeerId::from(local_key.public());
+    info!("Local peer id: {}", local_peer_id);
+
+    let transport = build_transport(&local_key)?;
+    // Create a Floodsub topic
+    let floodsub_topic = Topic::new("blocks");
+
+    // Create a Swarm to manage peers and events
+    let mut swarm = {
+        let mdns = if config.disable_mdns {
+            None
+        } else {
+            Some(Mdns::new(Default::default(), local_peer_id).context(
+                "Failed to start mDNS discovery, is a multicast capable network interface up?",
+            )?)
+        };
+        let mut behaviour = P2PBlockchainBehaviour {
+            duplicate_connections: DuplicateConnections::new(
+                local_peer_id,
+                config.max_connections_per_peer,
+            ),
+            connection_limits: connection_limits::Behaviour::new(
+                ConnectionLimits::default().with_max_established(config.max_established_total),
+            ),
+            blocked_peers: allow_block_list::Behaviour::default(),
+            floodsub: Floodsub::new(local_peer_id),
+            mdns: mdns.into(),
+            ping: ping::Behaviour::default(),
+        };
+
+        behaviour.floodsub.subscribe(floodsub_topic.clone());
+        SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id).build()
+    };
+
+    let mut initial_peers = Vec::new();
+
+    if let Some(file_path) = &config.initial_peers_file_path {
+        initial_peers.extend(
+            std::fs::read_to_string(file_path)
+                .with_context(|| format!("Failed to read initial peers file {}", file_path))?
+                .lines()
+                .map(parse_initial_peer)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| format!("Invalid initial peers file {}", file_path))?,
+        );
+    }
+
+    if let Some(peers) = &config.initial_peers {
+        initial_peers.extend(
+            peers
+                .iter()
+                .map(|s| parse_initial_peer(s))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        );
+    }
+
+    // Peers known before the last shutdown, with the lowest priority
+    let peer_store_path = config.data_dir.join(PEER_STORE_PATH);
+    let stored_peers = PeerStore::load(&peer_store_path)?;
+    initial_peers.extend(stored_peers.iter().map(|address| (address.clone(), 0)));
+
+    // Listen on all interfaces, on the configured port or the one of the previous
+    // run. The OS assigns one on the first run, or when the previous one is taken.
+    let listen_port_path = config.data_dir.join(LISTEN_PORT_PATH);
+    match config.p2p_port {
+        Some(port) => {
+            swarm
+                .listen_on(format!("/ip4/0.0.0.0/tcp/{}", port).parse()?)
+                .with_context(|| {
+                    format!("Failed to listen for peer connections on port {}", port)
+                })?;
+        }
+        None => {
+            let previous_port = std::fs::read_to_string(&listen_port_path)
+                .ok()
+                .and_then(|port| port.trim().parse::<u16>().ok())
+                .unwrap_or(0);
+            if let Err(e) = swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{}", previous_port).parse()?)
+            {
+                warn!(
+                    "Failed to listen on port {} of the previous run, using another one: {}",
+                    previous_port, e
+                );
+                swarm
+                    .listen_on("/ip4/0.0.0.0/tcp/0".parse()?)
+                    .context("Failed to listen for peer connections")?;
+            }
+        }
+    }
+
+    // Start handling incoming messages
+    let peer_store = Arc::new(std::sync::Mutex::new(PeerStore::with_addresses(
+        &stored_peers,
+    )));
+    let (network_command_sender, network_command_receiver) = mpsc::channel(16);
+    let network_status = Arc::new(NetworkStatus::new(
+        config.isolation_threshold_secs.map(Duration::from_secs),
+    ));
+    let swarm_task = tokio::spawn(handle_swarm_events(
+        swarm,
+        InitialPeerDialer::new(
+            initial_peers,
+            config.initial_dial_attempts,
+            config.initial_dial_fanout,
+        ),
+        Arc::clone(&peer_store),
+        listen_port_path,
+        Arc::clone(&network_status),
+        network_command_receiver,
+        Duration::from_secs(config.peer_ban_secs),
+    ));
+
+    let ledgers = Arc::new(ledgers);
+    let prune_task = config.retain_transactions.map(|retain| {
+        let ledgers = Arc::clone(&ledgers);
+        let mut prune_interval =
+            tokio::time::interval(Duration::from_secs(config.prune_interval_secs));
+        tokio::spawn(async move {
+            loop {
+                prune_interval.tick().await;
+                let ledgers = Arc::clone(&ledgers);
+                match tokio::task::spawn_blocking(move || ledgers.prune_all(retain)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("Failed to prune transaction history: {}", e),
+                    Err(e) => error!("Pruning task failed: {}", e),
+                }
+            }
+        })
+    });
+
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    let rpc_network_commands = network_command_sender.clone();
+    let task = tokio::spawn(async move {
+        tokio::select! {
+            result = run_http_rpc_server(
+                Arc::clone(&ledgers),
+                network_status,
+                rpc_network_commands,
+                RpcConfig {
+                    port: config.rpc_port,
+                    gzip_threshold: config.rpc_gzip_threshold,
+                    max_request_size: config.rpc_max_request_size,
+                    drain_grace_period: Duration::from_secs(config.drain_grace_period_secs),
+                    transaction_workers: config.transaction_workers,
+                    admin_token: config.rpc_admin_token.clone(),
+                    unix_socket: config.rpc_unix_socket.clone(),
+                },
+            ) => result.map_err(|e| anyhow!("RPC server failed: {}", e))?,
+            _ = shutdown_receiver => {}
+        }
+
+        // The RPC server is stopped at this point, so no new work comes in
+        swarm_task.abort();
+        if let Some(prune_task) = prune_task {
+            prune_task.abort();
+        }
+        peer_store
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .save(&peer_store_path)
+            .context("Failed to save the peer store")?;
+        ledgers
+            .sync_all()
+            .context("Failed to flush the databases")?;
+        if let Some(path) = &config.rpc_unix_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        info!("Peer store and databases flushed to disk");
+
+        Ok(())
+    });
+
+    Ok(RunningNode {
+        local_peer_id,
+        shutdown_sender,
+        task,
+        network_commands: network_command_sender,
+    })
+}
+
+/// Reads the node key, or generates one and writes it to `path` on the first run
+/// TCP transport authenticated with noise and multiplexed with yamux. Dials of an
+/// address ending with `/p2p/<peer id>` fail with `DialError::WrongPeerId` when
+/// the remote authenticates as another peer.
+fn build_transport(local_key: &identity::Keypair) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    // The noise handshake must authenticate the node with the same key its
+    // peer id is derived from, otherwise remote peers can't verify who they dialed
+    let noise_config = noise::Config::new(local_key)
+        .context("Failed to create the noise config, is the node key valid?")?;
+
+    Ok(TokioTransport::new(tcp::Config::default().nodelay(true))
+        .upgrade(Version::V1Lazy)
+        .authenticate(noise_config)
+        .multiplex(yamux::Config::default())
+        .boxed())
+}
+
+fn load_or_create_node_key(path: &Path) -> Result<identity::Keypair> {
+    match std::fs::read(path) {
+        Ok(bytes) => identity::Keypair::from_protobuf_encoding(&bytes)
+            .with_context(|| format!("Invalid node key in {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = identity::Keypair::generate_ed25519();
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options
+                .open(path)
+                .with_context(|| format!("Failed to create node key {}", path.display()))?;
+            std::io::Write::write_all(&mut file, &key.to_protobuf_encoding()?)
+                .with_context(|| format!("Failed to write node key {}", path.display()))?;
+
+            Ok(key)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read node key {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::transaction::Transaction;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const A: Address = Address([1; 32]);
+    const B: Address = Address([2; 32]);
+
+    fn config(dir: &Path, data_dir: &Path, args: &[&str]) -> NodeConfig {
+        let genesis_file_path = dir.join("genesis.json");
+        std::fs::write(
+            &genesis_file_path,
+            format!(r#"{{"balances": {{"{}": 1000}}}}"#, A.as_hex()),
+        )
+        .unwrap();
+
+        NodeConfig::parse_from(
+            [
+                "enokiweave",
+                "--genesis-file-path",
+                genesis_file_path.to_str().unwrap(),
+                "--data-dir",
+                data_dir.to_str().unwrap(),
+                "--rpc-port",
+                "0",
+                "--rpc-unix-socket",
+                dir.join("rpc.sock").to_str().unwrap(),
+            ]
+            .iter()
+            .chain(args),
+        )
+    }
+
+    /// Sends a JSON-RPC request over the unix socket and returns its result
+    async fn rpc(socket_path: &Path, request: serde_json::Value) -> serde_json::Value {
+        let mut socket = loop {
+            match tokio::net::UnixStream::connect(socket_path).await {
+                Ok(socket) => break socket,
+                // The RPC server starts in the background
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        let body = request.to_string();
+        socket
+            .write_all(
+                format!(
+                    "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let response: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert!(response.get("error").is_none(), "{}", response);
+        response["result"].clone()
+    }
+
+    fn submit_transaction(from: Address, to: Address, amount: u64) -> serde_json::Value {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let transaction = Transaction::new(from, to, amount).unwrap();
+        let id = transaction.calculate_id(None).unwrap();
+        let signature = signing_key.sign(&id);
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "submitTransaction",
+            "params": [{
+                "from": from.as_hex(),
+                "to": to.as_hex(),
+                "amount": amount,
+                "public_key": hex::encode(signing_key.verifying_key().as_bytes()),
+                "signature": {
+                    "R": hex::encode(signature.r_bytes()),
+                    "s": hex::encode(signature.s_bytes()),
+                },
+                "timestamp": transaction.timestamp,
+                "id": hex::encode(id),
+            }],
+            "id": 1,
+        })
+    }
+
+    #[tokio::test]
+    async fn node_processes_a_transaction_and_shuts_down() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        let socket_path = dir.path().join("rpc.sock");
+
+        let node = run_node(config(dir.path(), &data_dir, &[])).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            rpc(&socket_path, submit_transaction(A, B, 100)).await;
+            let balance = rpc(
+                &socket_path,
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "method": "addressBalance",
+                    "params": B.as_hex(),
+                    "id": 2,
+                }),
+            )
+            .await;
+            assert_eq!(balance, "100");
+        })
+        .await
+        .unwrap();
+        node.shutdown().await.unwrap();
+
+        assert!(!socket_path.exists());
+        assert!(data_dir.join(PEER_STORE_PATH).exists());
+        let manager = TransactionManager::new(&data_dir.join(DB_NAME)).unwrap();
+        assert_eq!(
+            manager.get_address_balance_and_selfchain_height(B).unwrap(),
+            (100, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn node_reports_a_data_dir_that_cannot_be_created() {
+        let dir = tempfile::tempdir().unwrap();
+        // A file stands where the data directory should be created
+        let data_dir = dir.path().join("not-a-directory");
+        std::fs::write(&data_dir, "").unwrap();
+
+        let error = run_node(config(dir.path(), &data_dir, &[]))
+            .await
+            .err()
+            .unwrap();
+
+        let message = format!("{:#}", error);
+        assert!(
+            message.contains("Failed to open the database in"),
+            "{}",
+            message
+        );
+        assert!(message.contains("not-a-directory"), "{}", message);
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Node in its own directory, without mDNS so that it only connects to the
+    /// peers it dials or is dialed by
+    async fn start_peer(dir: &Path, args: &[&str]) -> RunningNode {
+        let args: Vec<&str> = ["--disable-mdns"].iter().chain(args).copied().collect();
+        run_node(config(dir, &dir.join("data"), &args))
+            .await
+            .unwrap()
+    }
+
+    /// Waits until `node` reports a connection with `peer_id`
+    async fn connected_peer(node: &RunningNode, peer_id: PeerId) -> PeerInfo {
+        tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                let peer = node
+                    .peers()
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .find(|peer| peer.peer_id == peer_id.to_string() && peer.connected);
+                if let Some(peer) = peer {
+                    return peer;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("the peers never connected")
+    }
+
+    #[tokio::test]
+    async fn peers_report_connected_peers_and_their_addresses() {
+        let (dir_a, dir_b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let port = free_port().to_string();
+        let node_a = start_peer(dir_a.path(), &["--p2p-port", &port]).await;
+        let address_a = format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", port, node_a.local_peer_id());
+        let node_b = start_peer(dir_b.path(), &["--initial-peers", &address_a]).await;
+
+        let a_seen_by_b = connected_peer(&node_b, node_a.local_peer_id()).await;
+        assert_eq!(a_seen_by_b.address, address_a);
+        assert_eq!(a_seen_by_b.direction, Some("outbound"));
+        let b_seen_by_a = connected_peer(&node_a, node_b.local_peer_id()).await;
+        assert!(b_seen_by_a.address.starts_with("/ip4/127.0.0.1/tcp/"));
+        assert_eq!(b_seen_by_a.direction, Some("inbound"));
+
+        let peer_b = node_b.local_peer_id();
+        node_b.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while is_connected(&node_a, peer_b).await {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("the peer never disconnected");
+        let peers = node_a.peers().await.unwrap();
+        assert!(peers.iter().any(|peer| peer.peer_id == peer_b.to_string()));
+
+        node_a.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dialing_a_node_under_another_peer_id_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = free_port().to_string();
+        let node = start_peer(dir.path(), &["--p2p-port", &port]).await;
+
+        let key = identity::Keypair::generate_ed25519();
+        let mut swarm = SwarmBuilder::with_tokio_executor(
+            build_transport(&key).unwrap(),
+            libp2p::swarm::dummy::Behaviour,
+            PeerId::from(key.public()),
+        )
+        .build();
+        let address = format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", port, PeerId::random());
+        swarm
+            .dial(address.parse::<libp2p::Multiaddr>().unwrap())
+            .unwrap();
+
+        let error = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match swarm.select_next_some().await {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        panic!("connected to {} under another peer id", peer_id)
+                    }
+                    SwarmEvent::OutgoingConnectionError { error, .. } => return error,
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(
+            matches!(error, DialError::WrongPeerId { obtained, .. } if obtained == node.local_peer_id())
+        );
+
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stored_peers_are_reconnected_after_a_restart() {
+        let (dir_a, dir_b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let port = free_port().to_string();
+
+        let node_a = start_peer(dir_a.path(), &["--p2p-port", &port]).await;
+        let peer_a = node_a.local_peer_id();
+        let address_a = format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", port, peer_a);
+        let node_b = start_peer(dir_b.path(), &["--initial-peers", &address_a]).await;
+        connected_peer(&node_b, peer_a).await;
+        node_b.shutdown().await.unwrap();
+        node_a.shutdown().await.unwrap();
+
+        assert_eq!(
+            PeerStore::load(&dir_b.path().join("data").join(PEER_STORE_PATH)).unwrap(),
+            vec![address_a.parse().unwrap()]
+        );
+
+        // a keeps its identity and port, and b only knows it from its peer store
+        let node_a = start_peer(dir_a.path(), &[]).await;
+        assert_eq!(node_a.local_peer_id(), peer_a);
+        let node_b = start_peer(dir_b.path(), &[]).await;
+        connected_peer(&node_b, peer_a).await;
+
+        node_b.shutdown().await.unwrap();
+        node_a.shutdown().await.unwrap();
+    }
+
+    async fn is_connected(node: &RunningNode, peer_id: PeerId) -> bool {
+        node.peers()
+            .await
+            .unwrap()
+            .iter()
+            .any(|peer| peer.peer_id == peer_id.to_string() && peer.connected)
+    }
+
+    #[tokio::test]
+    async fn disconnected_peer_is_refused_during_its_ban() {
+        let (dir_a, dir_b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let port = free_port().to_string();
+        let node_a = start_peer(dir_a.path(), &["--p2p-port", &port]).await;
+        let address_a = format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", port, node_a.local_peer_id());
+        let node_b = start_peer(dir_b.path(), &["--initial-peers", &address_a]).await;
+        connected_peer(&node_a, node_b.local_peer_id()).await;
+
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        node_a
+            .network_commands
+            .send(NetworkCommand::DisconnectPeer(
+                node_b.local_peer_id(),
+                reply_sender,
+            ))
+            .await
+            .unwrap();
+        assert!(reply_receiver.await.unwrap());
+
+        // b redials a on restart, with the same peer id, and a closes the
+        // connection as soon as it is established until the ban expires
+        let peer_b = node_b.local_peer_id();
+        node_b.shutdown().await.unwrap();
+        let node_b = start_peer(dir_b.path(), &["--initial-peers", &address_a]).await;
+        assert_eq!(node_b.local_peer_id(), peer_b);
+        for _ in 0..20 {
+            assert!(!is_connected(&node_a, peer_b).await);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        node_b.shutdown().await.unwrap();
+        node_a.shutdown().await.unwrap();
+    }
+}
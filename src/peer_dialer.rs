@@ -0,0 +1,417 @@
+use anyhow::{anyhow, Result};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::NetworkBehaviour;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use tracing::{info, trace, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// Some dial errors don't name the address they are about. A dial that isn't
+// resolved within this delay is counted as failed.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+enum DialState {
+    Waiting(Instant),
+    // Time the dial started
+    Dialing(Instant),
+    Connected,
+    Failed,
+}
+
+struct InitialPeer {
+    address: Multiaddr,
+    priority: u32,
+    attempts: u32,
+    state: DialState,
+}
+
+/// Parses an initial peer given as `<multiaddr> [priority]`. Peers without a
+/// priority get the lowest one, 0.
+pub fn parse_initial_peer(line: &str) -> Result<(Multiaddr, u32)> {
+    let mut parts = line.split_whitespace();
+    let address = parts
+        .next()
+        .ok_or_else(|| anyhow!("Empty initial peer"))?
+        .parse::<Multiaddr>()
+        .map_err(|e| anyhow!("Invalid initial peer address {}: {}", line, e))?;
+    let priority = match parts.next() {
+        Some(priority) => priority
+            .parse::<u32>()
+            .map_err(|e| anyhow!("Invalid initial peer priority {}: {}", line, e))?,
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return Err(anyhow!(
+            "Invalid initial peer {}: expected <multiaddr> [priority]",
+            line
+        ));
+    }
+
+    Ok((address, priority))
+}
+
+/// Peer id embedded in the `/p2p/` component of a multiaddr, if any
+pub fn expected_peer_id(address: &Multiaddr) -> Option<PeerId> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(multihash) => PeerId::from_multihash(multihash).ok(),
+        _ => None,
+    })
+}
+
+/// Dials the initial peers once the node is listening, retrying failed dials
+/// with an exponential backoff up to `max_attempts` times per peer.
+///
+/// Peers are dialed by descending priority. Every peer of a priority is dialed,
+/// while peers of a lower priority are only dialed once every peer of the higher
+/// priorities has been given up on. At most `max_parallel_dials` dials are in
+/// flight at any time.
+pub struct InitialPeerDialer {
+    // Sorted by descending priority
+    peers: Vec<InitialPeer>,
+    max_attempts: u32,
+    max_parallel_dials: usize,
+    started: bool,
+    // Addresses given up on since the last call to `take_given_up`
+    given_up: Vec<Multiaddr>,
+}
+
+impl InitialPeerDialer {
+    pub fn new(peers: Vec<(Multiaddr, u32)>, max_attempts: u32, max_parallel_dials: usize) -> Self {
+        let mut peers = peers;
+        // Stable, so peers of the same priority keep their configured order
+        peers.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        // An address listed twice keeps its highest priority
+        let mut seen = HashSet::new();
+        let peers = peers
+            .into_iter()
+            .filter(|(address, _)| seen.insert(address.clone()))
+            .map(|(address, priority)| InitialPeer {
+                address,
+                priority,
+                attempts: 0,
+                state: DialState::Waiting(Instant::now()),
+            })
+            .collect();
+
+        Self {
+            peers,
+            max_attempts: max_attempts.max(1),
+            max_parallel_dials: max_parallel_dials.max(1),
+            started: false,
+            given_up: Vec::new(),
+        }
+    }
+
+    /// Highest priority with a peer still to dial, or `None` once every peer of
+    /// a priority is settled and one of them is connected, or every peer has been
+    /// given up on
+    fn current_priority(&self) -> Option<u32> {
+        let mut start = 0;
+        while start < self.peers.len() {
+            let priority = self.peers[start].priority;
+            let end = start
+                + self.peers[start..]
+                    .iter()
+                    .take_while(|peer| peer.priority == priority)
+                    .count();
+            let tier = &self.peers[start..end];
+            start = end;
+
+            if tier
+                .iter()
+                .any(|peer| matches!(peer.state, DialState::Waiting(_) | DialState::Dialing(_)))
+            {
+                return Some(priority);
+            }
+            if tier
+                .iter()
+                .any(|peer| matches!(peer.state, DialState::Connected))
+            {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    /// Called once the swarm listens on at least one address
+    pub fn start(&mut self) {
+        if !self.started && !self.peers.is_empty() {
+            info!("Dialing {} initial peers", self.peers.len());
+        }
+        self.started = true;
+    }
+
+    /// Dials the peers of the current priority whose backoff has elapsed, up to
+    /// the parallel dial limit
+    pub fn dial_due<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>) {
+        if !self.started {
+            return;
+        }
+        let Some(priority) = self.current_priority() else {
+            return;
+        };
+
+        let now = Instant::now();
+        let timed_out: Vec<Multiaddr> = self
+            .peers
+            .iter()
+            .filter(|peer| matches!(peer.state, DialState::Dialing(at) if now - at >= DIAL_TIMEOUT))
+            .map(|peer| peer.address.clone())
+            .collect();
+        for address in timed_out {
+            self.on_dial_failed(&address, "dial timed out");
+        }
+
+        let mut dialing = self
+            .peers
+            .iter()
+            .filter(|peer| matches!(peer.state, DialState::Dialing(_)))
+            .count();
+        for index in 0..self.peers.len() {
+            if dialing >= self.max_parallel_dials {
+                break;
+            }
+
+            let peer = &mut self.peers[index];
+            if peer.priority != priority
+                || !matches!(peer.state, DialState::Waiting(at) if at <= now)
+            {
+                continue;
+            }
+
+            peer.attempts += 1;
+            dialing += 1;
+            peer.state = DialState::Dialing(now);
+            trace!(
+                "Dialing initial peer {} of priority {} (attempt {}/{})",
+                peer.address,
+                peer.priority,
+                peer.attempts,
+                self.max_attempts
+            );
+
+            if let Err(e) = swarm.dial(peer.address.clone()) {
+                let address = peer.address.clone();
+                self.on_dial_failed(&address, &e.to_string());
+                dialing -= 1;
+            }
+        }
+    }
+
+    pub fn on_connected(&mut self, address: &Multiaddr) {
+        if let Some(peer) = self.peers.iter_mut().find(|peer| &peer.address == address) {
+            if !matches!(peer.state, DialState::Connected) {
+                info!(
+                    "Connected to initial peer {} after {} attempt(s)",
+                    peer.address, peer.attempts
+                );
+            }
+            peer.state = DialState::Connected;
+        }
+    }
+
+    /// Marks the peers expected at `peer_id` as connected, whichever side
+    /// dialed. A dial racing an inbound connection from the same peer is denied
+    /// by the connection limits.
+    pub fn on_peer_connected(&mut self, peer_id: PeerId) {
+        let addresses: Vec<Multiaddr> = self
+            .peers
+            .iter()
+            .filter(|peer| expected_peer_id(&peer.address) == Some(peer_id))
+            .map(|peer| peer.address.clone())
+            .collect();
+        for address in addresses {
+            self.on_connected(&address);
+        }
+    }
+
+    /// Fails the in-flight dials of the peers expected at `peer_id`, for dial
+    /// errors that don't report the dialed address
+    pub fn on_peer_dial_failed(&mut self, peer_id: PeerId, error: &str) {
+        let addresses: Vec<Multiaddr> = self
+            .peers
+            .iter()
+            .filter(|peer| {
+                matches!(peer.state, DialState::Dialing(_))
+                    && expected_peer_id(&peer.address) == Some(peer_id)
+            })
+            .map(|peer| peer.address.clone())
+            .collect();
+        for address in addresses {
+            self.on_dial_failed(&address, error);
+        }
+    }
+
+    /// Gives up on the peer at `address` without retrying, for failures that a
+    /// retry can't fix, such as a peer that now has another identity
+    pub fn give_up(&mut self, address: &Multiaddr, error: &str) {
+        if let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|peer| &peer.address == address && matches!(peer.state, DialState::Dialing(_)))
+        {
+            peer.attempts = self.max_attempts;
+        }
+        self.on_dial_failed(address, error);
+    }
+
+    /// Addresses given up on since the last call
+    pub fn take_given_up(&mut self) -> Vec<Multiaddr> {
+        std::mem::take(&mut self.given_up)
+    }
+
+    pub fn on_dial_failed(&mut self, address: &Multiaddr, error: &str) {
+        let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|peer| &peer.address == address && matches!(peer.state, DialState::Dialing(_)))
+        else {
+            return;
+        };
+
+        if peer.attempts >= self.max_attempts {
+            warn!(
+                "Giving up on initial peer {} after {} attempt(s): {}",
+                peer.address, peer.attempts, error
+            );
+            peer.state = DialState::Failed;
+            self.given_up.push(peer.address.clone());
+            return;
+        }
+
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(peer.attempts - 1))
+            .min(MAX_BACKOFF);
+        trace!(
+            "Failed to dial initial peer {}, retrying in {:?}: {}",
+            peer.address,
+            backoff,
+            error
+        );
+        peer.state = DialState::Waiting(Instant::now() + backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dialer(peers: &[(&str, u32)]) -> InitialPeerDialer {
+        InitialPeerDialer::new(
+            peers
+                .iter()
+                .map(|(address, priority)| (address.parse().unwrap(), *priority))
+                .collect(),
+            3,
+            4,
+        )
+    }
+
+    #[test]
+    fn nothing_is_dialed_before_the_node_listens() {
+        use libp2p::core::{muxing::StreamMuxerBox, transport::dummy::DummyTransport};
+        use libp2p::swarm::{dummy, SwarmBuilder};
+        use libp2p::Transport;
+
+        // Every dial through this transport fails right away
+        let mut swarm = SwarmBuilder::without_executor(
+            DummyTransport::<(PeerId, StreamMuxerBox)>::new().boxed(),
+            dummy::Behaviour,
+            PeerId::random(),
+        )
+        .build();
+        let mut dialer = dialer(&[("/ip4/127.0.0.1/tcp/1", 0)]);
+
+        dialer.dial_due(&mut swarm);
+        assert_eq!(dialer.peers[0].attempts, 0);
+        assert!(matches!(dialer.peers[0].state, DialState::Waiting(at) if at <= Instant::now()));
+
+        dialer.start();
+        dialer.dial_due(&mut swarm);
+        assert_eq!(dialer.peers[0].attempts, 1);
+    }
+
+    #[test]
+    fn peers_of_the_same_priority_are_dialed_after_one_connects() {
+        let mut dialer = dialer(&[("/ip4/127.0.0.1/tcp/1", 0), ("/ip4/127.0.0.1/tcp/2", 0)]);
+
+        dialer.peers[0].state = DialState::Connected;
+        assert_eq!(dialer.current_priority(), Some(0));
+
+        dialer.peers[1].state = DialState::Failed;
+        assert_eq!(dialer.current_priority(), None);
+    }
+
+    #[test]
+    fn lower_priorities_wait_for_the_higher_ones() {
+        let mut dialer = dialer(&[
+            ("/ip4/127.0.0.1/tcp/1", 0),
+            ("/ip4/127.0.0.1/tcp/2", 5),
+            ("/ip4/127.0.0.1/tcp/3", 5),
+        ]);
+        assert_eq!(dialer.current_priority(), Some(5));
+
+        dialer.peers[0].state = DialState::Failed;
+        assert_eq!(dialer.current_priority(), Some(5));
+
+        dialer.peers[1].state = DialState::Failed;
+        assert_eq!(dialer.current_priority(), Some(0));
+    }
+
+    const PEER_ADDRESS: &str =
+        "/ip4/127.0.0.1/tcp/1/p2p/12D3KooWKknZdnepQRgcUKon35DfEiMPePX3xjoMGsvaVmEzVWrB";
+
+    #[test]
+    fn denied_dial_is_retried() {
+        let mut dialer = dialer(&[(PEER_ADDRESS, 0)]);
+        let peer_id = expected_peer_id(&PEER_ADDRESS.parse().unwrap()).unwrap();
+        dialer.peers[0].attempts = 1;
+        dialer.peers[0].state = DialState::Dialing(Instant::now());
+
+        dialer.on_peer_dial_failed(peer_id, "denied");
+        assert!(matches!(dialer.peers[0].state, DialState::Waiting(_)));
+    }
+
+    #[test]
+    fn peers_are_given_up_on_after_the_last_attempt() {
+        let mut dialer = dialer(&[("/ip4/127.0.0.1/tcp/1", 0), ("/ip4/127.0.0.1/tcp/2", 0)]);
+        let (first, second) = (
+            dialer.peers[0].address.clone(),
+            dialer.peers[1].address.clone(),
+        );
+        dialer.peers[0].attempts = 1;
+        dialer.peers[0].state = DialState::Dialing(Instant::now());
+        dialer.peers[1].attempts = 3;
+        dialer.peers[1].state = DialState::Dialing(Instant::now());
+
+        dialer.on_dial_failed(&second, "refused");
+        dialer.give_up(&first, "wrong peer id");
+
+        assert!(matches!(dialer.peers[0].state, DialState::Failed));
+        assert_eq!(dialer.take_given_up(), vec![second, first]);
+        assert!(dialer.take_given_up().is_empty());
+    }
+
+    #[test]
+    fn inbound_connection_settles_the_dial() {
+        let mut dialer = dialer(&[(PEER_ADDRESS, 0), ("/ip4/127.0.0.1/tcp/2", 0)]);
+        let peer_id = expected_peer_id(&PEER_ADDRESS.parse().unwrap()).unwrap();
+        dialer.peers[0].state = DialState::Dialing(Instant::now());
+
+        dialer.on_peer_connected(peer_id);
+        assert!(matches!(dialer.peers[0].state, DialState::Connected));
+        assert!(matches!(dialer.peers[1].state, DialState::Waiting(_)));
+    }
+
+    #[test]
+    fn lower_priorities_are_not_dialed_once_a_higher_one_is_connected() {
+        let mut dialer = dialer(&[("/ip4/127.0.0.1/tcp/1", 0), ("/ip4/127.0.0.1/tcp/2", 5)]);
+
+        dialer.peers[0].state = DialState::Connected;
+        assert_eq!(dialer.current_priority(), None);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::peer_dialer::expected_peer_id;
+use anyhow::{anyhow, Result};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Addresses of the peers the node has connected to or discovered, persisted on
+/// shutdown so they can be dialed again after a restart
+#[derive(Default)]
+pub struct PeerStore {
+    addresses: HashMap<PeerId, Multiaddr>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store holding the addresses loaded by [`PeerStore::load`], so the peers
+    /// that can't be reached during this run are still saved for the next one.
+    /// Addresses without a `/p2p/` component can't be matched to their peer and
+    /// are left out.
+    pub fn with_addresses(addresses: &[Multiaddr]) -> Self {
+        let mut store = Self::new();
+        for address in addresses {
+            if let Some(peer_id) = expected_peer_id(address) {
+                store.insert(peer_id, address.clone());
+            }
+        }
+        store
+    }
+
+    /// Records a dialable address of the peer. The peer id is appended as a
+    /// `/p2p/` component so that it is checked when the address is dialed again.
+    pub fn insert(&mut self, peer_id: PeerId, address: Multiaddr) {
+        let address = if matches!(address.iter().last(), Some(Protocol::P2p(_))) {
+            address
+        } else {
+            address.with(Protocol::P2p(peer_id.into()))
+        };
+        self.addresses.insert(peer_id, address);
+    }
+
+    /// Forgets the peer if `address` is still its recorded address, e.g. once
+    /// dialing it has been given up on
+    pub fn remove(&mut self, peer_id: PeerId, address: &Multiaddr) {
+        if self.addresses.get(&peer_id) == Some(address) {
+            self.addresses.remove(&peer_id);
+        }
+    }
+
+    /// Reads the addresses saved by [`PeerStore::save`], one per line. A missing
+    /// file yields no address.
+    pub fn load(path: &Path) -> Result<Vec<Multiaddr>> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+        };
+
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                line.trim()
+                    .parse::<Multiaddr>()
+                    .map_err(|e| anyhow!("Invalid address {} in peer store: {}", line, e))
+            })
+            .collect()
+    }
+
+    /// Writes the addresses to a temporary file and renames it over `path`, so a
+    /// crash mid-write never leaves a truncated peer store behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = std::fs::File::create(&tmp_path)
+                .map_err(|e| anyhow!("Failed to create {}: {}", tmp_path.display(), e))?;
+            for address in self.addresses.values() {
+                writeln!(file, "{}", address)?;
+            }
+            file.sync_all()?;
+        }
+
+        std::fs::rename(&tmp_path, path)
+            .map_err(|e| anyhow!("Failed to replace {}: {}", path.display(), e))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_ID: &str = "12D3KooWKknZdnepQRgcUKon35DfEiMPePX3xjoMGsvaVmEzVWrB";
+
+    #[test]
+    fn loaded_peers_are_saved_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers.txt");
+        let address: Multiaddr = format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", PEER_ID)
+            .parse()
+            .unwrap();
+        std::fs::write(&path, format!("{}\n", address)).unwrap();
+
+        // Never reached during this run
+        let store = PeerStore::with_addresses(&PeerStore::load(&path).unwrap());
+        store.save(&path).unwrap();
+
+        assert_eq!(PeerStore::load(&path).unwrap(), vec![address]);
+    }
+
+    #[test]
+    fn peers_given_up_on_are_removed() {
+        let peer_id: PeerId = PEER_ID.parse().unwrap();
+        let stale: Multiaddr = format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", PEER_ID)
+            .parse()
+            .unwrap();
+        let mut store = PeerStore::with_addresses(std::slice::from_ref(&stale));
+
+        store.insert(peer_id, "/ip4/10.0.0.2/tcp/4001".parse().unwrap());
+        // An older address of the peer leaves the current one in place
+        store.remove(peer_id, &stale);
+        assert_eq!(store.addresses.len(), 1);
+
+        let current = store.addresses[&peer_id].clone();
+        store.remove(peer_id, &current);
+        assert!(store.addresses.is_empty());
+    }
+
+    #[test]
+    fn new_address_of_a_loaded_peer_replaces_the_stored_one() {
+        let peer_id: PeerId = PEER_ID.parse().unwrap();
+        let mut store =
+            PeerStore::with_addresses(&[format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", PEER_ID)
+                .parse()
+                .unwrap()]);
+
+        store.insert(peer_id, "/ip4/10.0.0.2/tcp/4001".parse().unwrap());
+
+        let expected: Multiaddr = format!("/ip4/10.0.0.2/tcp/4001/p2p/{}", PEER_ID)
+            .parse()
+            .unwrap();
+        assert_eq!(
+            store.addresses.values().collect::<Vec<_>>(),
+            vec![&expected]
+        );
+    }
+}
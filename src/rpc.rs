@@ -1,166 +1,690 @@
 use anyhow::{anyhow, Result};
 use ed25519_dalek::VerifyingKey;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use libp2p::PeerId;
 use serde_json::Value as JsonValue;
+use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::sync::{mpsc, oneshot, Mutex};
-use tracing::{error, info, trace};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tracing::{error, info, trace, warn};
 
 use crate::address::Address;
-use crate::transaction::TransactionRequest;
-use crate::transaction_manager::TransactionManager;
+use crate::network_status::{NetworkCommand, NetworkStatus, PeerInfo};
+use crate::transaction::{Transaction, TransactionRequest};
+use crate::transaction_manager::{SelfchainState, TransactionManager, TransactionManagerRegistry};
 
 enum RPCRequest {
     Transfer(TransactionRequest),
     GetBalance(Address),
+    GetChainHeads(Vec<Address>),
+    Simulate(TransactionRequest, Option<SelfchainState>),
+}
+
+impl RPCRequest {
+    /// Address whose requests must be processed in order
+    fn ordering_address(&self) -> Option<Address> {
+        match self {
+            RPCRequest::Transfer(transaction) | RPCRequest::Simulate(transaction, _) => {
+                Some(transaction.from)
+            }
+            RPCRequest::GetBalance(address) => Some(*address),
+            RPCRequest::GetChainHeads(_) => None,
+        }
+    }
 }
 
 struct QueuedTransaction {
+    transaction_manager: Arc<TransactionManager>,
     request: RPCRequest,
-    response_sender: oneshot::Sender<Result<String, String>>,
+    response_sender: oneshot::Sender<Result<JsonValue, String>>,
+}
+
+/// Capacity of the queue of each worker
+const WORKER_QUEUE_CAPACITY: usize = 1000;
+
+/// Queues of the transaction workers. Requests are routed by the address they
+/// concern, so that the transactions of a sender are processed one at a time and
+/// in the order they were received, while different senders proceed in parallel.
+#[derive(Clone)]
+struct TransactionQueue {
+    workers: Vec<mpsc::Sender<(QueuedTransaction, PendingRequest)>>,
+    // Requests sent and not processed yet
+    pending: Arc<AtomicUsize>,
+}
+
+/// Counts a request as pending until it is processed and dropped by its worker
+struct PendingRequest(Arc<AtomicUsize>);
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl TransactionQueue {
+    fn new(
+        workers: usize,
+    ) -> (
+        Self,
+        Vec<mpsc::Receiver<(QueuedTransaction, PendingRequest)>>,
+    ) {
+        let (senders, receivers) = (0..workers.max(1))
+            .map(|_| mpsc::channel(WORKER_QUEUE_CAPACITY))
+            .unzip();
+        let queue = Self {
+            workers: senders,
+            pending: Arc::new(AtomicUsize::new(0)),
+        };
+        (queue, receivers)
+    }
+
+    async fn send(&self, queued_tx: QueuedTransaction) -> Result<()> {
+        let worker = self.worker_of(queued_tx.request.ordering_address());
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let pending = PendingRequest(Arc::clone(&self.pending));
+        self.workers[worker]
+            .send((queued_tx, pending))
+            .await
+            .map_err(|_| anyhow!("transaction workers have stopped"))
+    }
+
+    fn worker_of(&self, address: Option<Address>) -> usize {
+        match address {
+            Some(address) => {
+                let mut hasher = DefaultHasher::new();
+                address.hash(&mut hasher);
+                hasher.finish() as usize % self.workers.len()
+            }
+            None => 0,
+        }
+    }
+
+    /// Number of requests sent and not processed yet
+    fn len(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Whether every request sent so far has been processed
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Once draining, the node refuses new transactions, lets the queue empty and
+/// shuts down after the grace period
+struct DrainState {
+    draining: AtomicBool,
+    grace_period: Duration,
+    // Notified once the grace period has elapsed, which stops the server
+    drained: Notify,
+}
+
+/// Aborts a task spawned by the server when the server future is dropped
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub struct RpcConfig {
+    pub port: u16,
+    /// Minimum response size, in bytes, before gzip is applied
+    pub gzip_threshold: usize,
+    /// Maximum size, in bytes, of a request with its headers
+    pub max_request_size: usize,
+    pub drain_grace_period: Duration,
+    /// Number of workers processing the transaction queue concurrently
+    pub transaction_workers: usize,
+    /// Token expected in the `auth` field of admin requests
+    pub admin_token: Option<String>,
+    /// Path of a unix domain socket served alongside the TCP port
+    pub unix_socket: Option<PathBuf>,
+}
+
+/// State shared by the connections of the RPC server
+#[derive(Clone)]
+struct RpcState {
+    tx_queue_sender: TransactionQueue,
+    drain_state: Arc<DrainState>,
+    ledgers: Arc<TransactionManagerRegistry>,
+    network_status: Arc<NetworkStatus>,
+    network_admin: Arc<NetworkAdmin>,
+    gzip_threshold: usize,
+    max_request_size: usize,
+}
+
+/// Access to the swarm for the admin methods
+struct NetworkAdmin {
+    commands: mpsc::Sender<NetworkCommand>,
+    status: Arc<NetworkStatus>,
+    token: Option<String>,
+}
+
+impl NetworkAdmin {
+    fn authorize(&self, req: &JsonValue) -> Result<()> {
+        let Some(token) = &self.token else {
+            return Err(anyhow!(
+                "Admin methods are disabled, start the node with --rpc-admin-token"
+            ));
+        };
+        match req.get("auth").and_then(JsonValue::as_str) {
+            // Compared in constant time so the token can't be guessed byte by byte
+            Some(auth) if bool::from(auth.as_bytes().ct_eq(token.as_bytes())) => Ok(()),
+            _ => Err(anyhow!("Unauthorized")),
+        }
+    }
+
+    /// Destructive admin methods only act with `"confirm": true`, and otherwise
+    /// return what they would do
+    fn confirmed(req: &JsonValue) -> bool {
+        req.get("confirm").and_then(JsonValue::as_bool) == Some(true)
+    }
+
+    async fn peers(&self) -> Result<Vec<PeerInfo>> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.commands
+            .send(NetworkCommand::Peers(response_sender))
+            .await
+            .map_err(|e| anyhow!("Failed to send peers command: {}", e))?;
+
+        response_receiver
+            .await
+            .map_err(|e| anyhow!("Failed to receive peers: {}", e))
+    }
 }
 
 pub async fn run_http_rpc_server(
-    transaction_manager: Arc<Mutex<TransactionManager>>,
-    rpc_port: u16,
+    ledgers: Arc<TransactionManagerRegistry>,
+    network_status: Arc<NetworkStatus>,
+    network_commands: mpsc::Sender<NetworkCommand>,
+    config: RpcConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let addr = SocketAddr::from(([127, 0, 0, 1], rpc_port));
+    let addr = SocketAddr::from(([127, 0, 0, 1], config.port));
     let listener = TcpListener::bind(addr).await?;
     info!("RPC server listening on {}", addr);
 
-    // Create channel for transaction queue
-    let (tx_queue_sender, mut tx_queue_receiver) = mpsc::channel::<QueuedTransaction>(1000);
+    // Spawn transaction processor tasks, each with its own queue. The per-address
+    // locks of the transaction manager keep a transfer from racing transactions
+    // of another sender to the same recipient.
+    let (tx_queue_sender, tx_queue_receivers) = TransactionQueue::new(config.transaction_workers);
+    for tx_queue_receiver in tx_queue_receivers {
+        tokio::spawn(process_transaction_queue(tx_queue_receiver));
+    }
 
-    // Spawn transaction processor task
-    let transaction_manager_clone = Arc::clone(&transaction_manager);
-    tokio::spawn(async move {
-        process_transaction_queue(transaction_manager_clone, &mut tx_queue_receiver).await;
+    let gzip_threshold = config.gzip_threshold;
+    let max_request_size = config.max_request_size;
+    let network_admin = Arc::new(NetworkAdmin {
+        commands: network_commands,
+        status: Arc::clone(&network_status),
+        token: config.admin_token,
+    });
+    let drain_state = Arc::new(DrainState {
+        draining: AtomicBool::new(false),
+        grace_period: config.drain_grace_period,
+        drained: Notify::new(),
     });
 
-    loop {
-        let (mut socket, _) = listener.accept().await?;
-        let tx_queue_sender = tx_queue_sender.clone();
-
-        tokio::spawn(async move {
-            let mut buf = [0; 8192];
-            match socket.read(&mut buf).await {
-                Ok(0) => {
-                    trace!("Connection closed by client");
-                }
-                Ok(n) => {
-                    let request = String::from_utf8_lossy(&buf[..n]);
-
-                    if let Some(body_start) = request.find("\r\n\r\n") {
-                        let body = &request[body_start + 4..];
-                        trace!("Request body: {}", body);
-
-                        match serde_json::from_str::<serde_json::Value>(body) {
-                            Ok(rpc_request) => {
-                                match handle_rpc_request(&rpc_request, tx_queue_sender).await {
-                                    Ok(result) => {
-                                        let response = serde_json::json!({
-                                            "jsonrpc": "2.0",
-                                            "result": result,
-                                            "id": rpc_request["id"]
-                                        });
-
-                                        let response_body =
-                                            serde_json::to_string(&response).unwrap();
-                                        let http_response = format!(
-                                            "HTTP/1.1 200 OK\r\n\
-                                             Content-Type: application/json\r\n\
-                                             Content-Length: {}\r\n\
-                                             \r\n\
-                                             {}",
-                                            response_body.len(),
-                                            response_body
-                                        );
-
-                                        if let Err(e) =
-                                            socket.write_all(http_response.as_bytes()).await
-                                        {
-                                            error!("Failed to write response: {:?}", e);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        let error_response = serde_json::json!({
-                                            "jsonrpc": "2.0",
-                                            "error": {
-                                                "code": -32603,
-                                                "message": format!("Internal error: {}", e)
-                                            },
-                                            "id": rpc_request["id"]
-                                        });
-
-                                        let response_body =
-                                            serde_json::to_string(&error_response).unwrap();
-                                        let http_response = format!(
-                                            "HTTP/1.1 500 Internal Server Error\r\n\
-                                             Content-Type: application/json\r\n\
-                                             Content-Length: {}\r\n\
-                                             \r\n\
-                                             {}",
-                                            response_body.len(),
-                                            response_body
-                                        );
-
-                                        if let Err(e) =
-                                            socket.write_all(http_response.as_bytes()).await
-                                        {
-                                            error!("Failed to write error response: {:?}", e);
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                let error_response = serde_json::json!({
-                                    "jsonrpc": "2.0",
-                                    "error": {
-                                        "code": -32700,
-                                        "message": format!("Parse error: {}", e)
-                                    },
-                                    "id": null
-                                });
-
-                                let response_body = serde_json::to_string(&error_response).unwrap();
-                                let http_response = format!(
-                                    "HTTP/1.1 400 Bad Request\r\n\
-                                     Content-Type: application/json\r\n\
-                                     Content-Length: {}\r\n\
-                                     \r\n\
-                                     {}",
-                                    response_body.len(),
-                                    response_body
-                                );
-
-                                if let Err(e) = socket.write_all(http_response.as_bytes()).await {
-                                    error!("Failed to write parse error response: {:?}", e);
-                                }
-                            }
-                        }
-                    } else {
-                        error!("Invalid HTTP request format");
-                        let error_response = "HTTP/1.1 400 Bad Request\r\n\r\n";
-                        if let Err(e) = socket.write_all(error_response.as_bytes()).await {
-                            error!("Failed to write error response: {:?}", e);
-                        }
+    let state = RpcState {
+        tx_queue_sender,
+        drain_state,
+        ledgers,
+        network_status,
+        network_admin,
+        gzip_threshold,
+        max_request_size,
+    };
+
+    #[cfg(not(unix))]
+    if config.unix_socket.is_some() {
+        return Err("Unix domain sockets are only supported on unix targets".into());
+    }
+    #[cfg(unix)]
+    let _unix_listener_task = if let Some(path) = &config.unix_socket {
+        let unix_listener = bind_unix_socket(path)?;
+        info!("RPC server listening on {}", path.display());
+
+        let state = state.clone();
+        Some(AbortOnDrop(tokio::spawn(async move {
+            loop {
+                match unix_listener.accept().await {
+                    Ok((socket, _)) => {
+                        tokio::spawn(handle_connection(socket, state.clone()));
                     }
+                    Err(e) => error!("Failed to accept unix socket connection: {:?}", e),
                 }
-                Err(e) => error!("Failed to read from socket: {:?}", e),
             }
-        });
+        })))
+    } else {
+        None
+    };
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, _) = accepted?;
+                tokio::spawn(handle_connection(socket, state.clone()));
+            }
+            _ = state.drain_state.drained.notified() => {
+                info!("Node drained, stopping the RPC server");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Binds the unix socket at `path`, replacing a socket left behind by a
+/// previous run. Any other kind of file at `path` is left untouched.
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+
+    UnixListener::bind(path)
+}
+
+enum ReadRequest {
+    /// Headers and body of the request
+    Complete(Vec<u8>),
+    Closed,
+    TooLarge,
+    InvalidContentLength,
+}
+
+/// Reads the headers of a request, then its body up to its `Content-Length`.
+/// A request without the header has no body.
+async fn read_request<S>(socket: &mut S, max_request_size: usize) -> std::io::Result<ReadRequest>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut request = Vec::new();
+    let mut buf = [0; 8192];
+    // Size of the request once its headers are read
+    let mut expected_size = None;
+
+    loop {
+        if let Some(expected_size) = expected_size {
+            if request.len() >= expected_size {
+                request.truncate(expected_size);
+                return Ok(ReadRequest::Complete(request));
+            }
+        }
+
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            // Left to the parser, which rejects a truncated request
+            return Ok(if request.is_empty() {
+                ReadRequest::Closed
+            } else {
+                ReadRequest::Complete(request)
+            });
+        }
+        request.extend_from_slice(&buf[..n]);
+
+        if expected_size.is_none() {
+            if let Some(headers_end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                let headers = String::from_utf8_lossy(&request[..headers_end]);
+                let content_length = match content_length(&headers) {
+                    Some(Ok(content_length)) => content_length,
+                    Some(Err(_)) => return Ok(ReadRequest::InvalidContentLength),
+                    None => 0,
+                };
+                expected_size = Some(headers_end.saturating_add(4).saturating_add(content_length));
+            }
+        }
+
+        if expected_size.unwrap_or(request.len()) > max_request_size {
+            return Ok(ReadRequest::TooLarge);
+        }
+    }
+}
+
+fn content_length(headers: &str) -> Option<Result<usize, std::num::ParseIntError>> {
+    headers.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("content-length")
+            .then(|| value.trim().parse::<usize>())
+    })
+}
+
+/// Reads a single HTTP request from the connection and writes its response
+async fn handle_connection<S>(mut socket: S, state: RpcState)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let RpcState {
+        tx_queue_sender,
+        drain_state,
+        ledgers,
+        network_status,
+        network_admin,
+        gzip_threshold,
+        max_request_size,
+    } = state;
+
+    let request = match read_request(&mut socket, max_request_size).await {
+        Ok(ReadRequest::Complete(request)) => request,
+        Ok(ReadRequest::Closed) => {
+            trace!("Connection closed by client");
+            return;
+        }
+        Ok(ReadRequest::TooLarge) => {
+            warn!("Request over {} bytes refused", max_request_size);
+            let error_response = "HTTP/1.1 413 Payload Too Large\r\n\r\n";
+            if let Err(e) = socket.write_all(error_response.as_bytes()).await {
+                error!("Failed to write error response: {:?}", e);
+            }
+            return;
+        }
+        Ok(ReadRequest::InvalidContentLength) => {
+            error!("Invalid Content-Length header");
+            let error_response = "HTTP/1.1 400 Bad Request\r\n\r\n";
+            if let Err(e) = socket.write_all(error_response.as_bytes()).await {
+                error!("Failed to write error response: {:?}", e);
+            }
+            return;
+        }
+        Err(e) => {
+            error!("Failed to read from socket: {:?}", e);
+            return;
+        }
+    };
+
+    let request = String::from_utf8_lossy(&request);
+
+    if let Some(body_start) = request.find("\r\n\r\n") {
+        let use_gzip = accepts_gzip(&request[..body_start]);
+
+        if request.starts_with("GET /health ") {
+            // An isolated node can't reach the rest of the network, unlike
+            // a connected node which simply has no traffic
+            let (status, health) = if drain_state.draining.load(Ordering::SeqCst) {
+                ("503 Service Unavailable", "draining")
+            } else if network_status.is_isolated() {
+                ("503 Service Unavailable", "isolated")
+            } else {
+                ("200 OK", "ok")
+            };
+            let response_body = serde_json::json!({
+                "status": health,
+                "connected_peers": network_status.connected_peers(),
+            })
+            .to_string();
+            let http_response =
+                build_http_response(status, &response_body, use_gzip, gzip_threshold);
+
+            if let Err(e) = socket.write_all(&http_response).await {
+                error!("Failed to write health response: {:?}", e);
+            }
+            return;
+        }
+
+        let body = &request[body_start + 4..];
+        trace!("Request body: {}", body);
+
+        let (status, response) = match serde_json::from_str::<serde_json::Value>(body) {
+            Ok(JsonValue::Array(batch)) => {
+                process_rpc_batch(batch, tx_queue_sender, drain_state, ledgers, network_admin).await
+            }
+            Ok(rpc_request) => {
+                process_rpc_request(
+                    rpc_request,
+                    tx_queue_sender,
+                    drain_state,
+                    ledgers,
+                    network_admin,
+                )
+                .await
+            }
+            Err(e) => (
+                "400 Bad Request",
+                Some(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "error": {
+                        "code": -32700,
+                        "message": format!("Parse error: {}", e)
+                    },
+                    "id": null
+                })),
+            ),
+        };
+
+        // Notifications get no response body
+        let http_response = match response {
+            Some(response) => build_http_response(
+                status,
+                &serde_json::to_string(&response).unwrap(),
+                use_gzip,
+                gzip_threshold,
+            ),
+            None => b"HTTP/1.1 204 No Content\r\n\r\n".to_vec(),
+        };
+
+        if let Err(e) = socket.write_all(&http_response).await {
+            error!("Failed to write response: {:?}", e);
+        }
+    } else {
+        error!("Invalid HTTP request format");
+        let error_response = "HTTP/1.1 400 Bad Request\r\n\r\n";
+        if let Err(e) = socket.write_all(error_response.as_bytes()).await {
+            error!("Failed to write error response: {:?}", e);
+        }
+    }
+}
+
+fn invalid_request(message: &str) -> JsonValue {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "error": {
+            "code": -32600,
+            "message": format!("Invalid Request: {}", message)
+        },
+        "id": null
+    })
+}
+
+/// Runs a parsed JSON-RPC request and returns the HTTP status along with the
+/// response object, or `None` for a notification
+async fn process_rpc_request(
+    rpc_request: JsonValue,
+    tx_queue_sender: TransactionQueue,
+    drain_state: Arc<DrainState>,
+    ledgers: Arc<TransactionManagerRegistry>,
+    network_admin: Arc<NetworkAdmin>,
+) -> (&'static str, Option<JsonValue>) {
+    if !rpc_request.is_object() {
+        return (
+            "400 Bad Request",
+            Some(invalid_request("expected an object")),
+        );
+    }
+
+    // A request without an id is a notification: it is processed but the client
+    // does not expect a response
+    let Some(id) = rpc_request.get("id").cloned() else {
+        if let Err(e) = handle_rpc_request(
+            &rpc_request,
+            tx_queue_sender,
+            drain_state,
+            ledgers,
+            network_admin,
+        )
+        .await
+        {
+            error!("Failed to process notification: {}", e);
+        }
+        return ("204 No Content", None);
+    };
+
+    // JSON-RPC ids must be a string, a number or null
+    if !(id.is_string() || id.is_number() || id.is_null()) {
+        return (
+            "400 Bad Request",
+            Some(invalid_request("id must be a string, a number or null")),
+        );
+    }
+
+    match handle_rpc_request(
+        &rpc_request,
+        tx_queue_sender,
+        drain_state,
+        ledgers,
+        network_admin,
+    )
+    .await
+    {
+        Ok(result) => (
+            "200 OK",
+            Some(serde_json::json!({
+                "jsonrpc": "2.0",
+                "result": result,
+                "id": id
+            })),
+        ),
+        Err(e) => (
+            "500 Internal Server Error",
+            Some(serde_json::json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32603,
+                    "message": format!("Internal error: {}", e)
+                },
+                "id": id
+            })),
+        ),
+    }
+}
+
+/// Runs each request of a batch in order. Notifications are left out of the
+/// response array, and a batch made only of notifications gets no response.
+async fn process_rpc_batch(
+    batch: Vec<JsonValue>,
+    tx_queue_sender: TransactionQueue,
+    drain_state: Arc<DrainState>,
+    ledgers: Arc<TransactionManagerRegistry>,
+    network_admin: Arc<NetworkAdmin>,
+) -> (&'static str, Option<JsonValue>) {
+    if batch.is_empty() {
+        return ("400 Bad Request", Some(invalid_request("empty batch")));
+    }
+
+    let mut responses = Vec::with_capacity(batch.len());
+    for rpc_request in batch {
+        let (_, response) = process_rpc_request(
+            rpc_request,
+            tx_queue_sender.clone(),
+            Arc::clone(&drain_state),
+            Arc::clone(&ledgers),
+            Arc::clone(&network_admin),
+        )
+        .await;
+        responses.extend(response);
+    }
+
+    if responses.is_empty() {
+        ("204 No Content", None)
+    } else {
+        ("200 OK", Some(JsonValue::Array(responses)))
     }
 }
 
+/// Whether Accept-Encoding lists gzip, other than with `q=0` which refuses it
+fn accepts_gzip(headers: &str) -> bool {
+    headers.lines().skip(1).any(|line| {
+        let Some((name, value)) = line.split_once(':') else {
+            return false;
+        };
+        name.trim().eq_ignore_ascii_case("accept-encoding")
+            && value.split(',').any(|encoding| {
+                let mut params = encoding.split(';');
+                params
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .eq_ignore_ascii_case("gzip")
+                    && params.all(|param| match param.split_once('=') {
+                        Some((key, q)) if key.trim().eq_ignore_ascii_case("q") => {
+                            q.trim().parse::<f32>().is_ok_and(|q| q > 0.0)
+                        }
+                        _ => true,
+                    })
+            })
+    })
+}
+
+// Bodies smaller than the threshold are sent as-is, since gzip would add more
+// overhead than it saves on them
+fn build_http_response(status: &str, body: &str, use_gzip: bool, gzip_threshold: usize) -> Vec<u8> {
+    if use_gzip && body.len() >= gzip_threshold {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        match encoder
+            .write_all(body.as_bytes())
+            .and_then(|_| encoder.finish())
+        {
+            Ok(compressed) => {
+                let mut http_response = format!(
+                    "HTTP/1.1 {}\r\n\
+                     Content-Type: application/json\r\n\
+                     Content-Encoding: gzip\r\n\
+                     Content-Length: {}\r\n\
+                     \r\n",
+                    status,
+                    compressed.len()
+                )
+                .into_bytes();
+                http_response.extend_from_slice(&compressed);
+                return http_response;
+            }
+            Err(e) => error!("Failed to gzip response, sending it uncompressed: {:?}", e),
+        }
+    }
+
+    format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
 async fn process_transaction_queue(
-    transaction_manager: Arc<Mutex<TransactionManager>>,
-    queue_receiver: &mut mpsc::Receiver<QueuedTransaction>,
+    mut queue_receiver: mpsc::Receiver<(QueuedTransaction, PendingRequest)>,
 ) {
-    while let Some(queued_tx) = queue_receiver.recv().await {
-        let result = process_single_transaction(&transaction_manager, queued_tx.request).await;
+    while let Some((queued_tx, _pending)) = queue_receiver.recv().await {
+        // LMDB access and signature verification are blocking work
+        let transaction_manager = queued_tx.transaction_manager;
+        let result = tokio::task::spawn_blocking(move || {
+            process_single_transaction(&transaction_manager, queued_tx.request)
+        })
+        .await
+        .unwrap_or_else(|e| Err(anyhow!("Transaction processing task failed: {}", e)));
 
         // Convert anyhow::Error to String for response sender
         let result = result.map_err(|e| e.to_string());
@@ -171,47 +695,97 @@ async fn process_transaction_queue(
     }
 }
 
-async fn process_single_transaction(
-    transaction_manager: &Arc<Mutex<TransactionManager>>,
+fn process_single_transaction(
+    manager: &TransactionManager,
     request: RPCRequest,
-) -> Result<String> {
-    let mut manager = transaction_manager.lock().await;
-
+) -> Result<JsonValue> {
     match request {
         RPCRequest::Transfer(transaction) => {
             match manager.add_transaction(
-                transaction.from,
-                transaction.to,
-                transaction.amount,
+                Transaction {
+                    from: transaction.from,
+                    to: transaction.to,
+                    amount: transaction.amount,
+                    timestamp: transaction.timestamp,
+                },
                 VerifyingKey::from_bytes(&transaction.public_key)
                     .map_err(|e| anyhow!("Invalid public key: {}", e))?,
-                transaction.timestamp,
                 transaction.signature,
+                transaction.refund_of.as_deref(),
             ) {
                 Ok(transaction_id) => {
                     trace!("Transaction added successfully with ID: {}", transaction_id);
-                    Ok(transaction_id.to_string())
+                    Ok(JsonValue::String(transaction_id))
                 }
                 Err(e) => Err(anyhow!("Error processing transaction: {}", e)),
             }
         }
+        RPCRequest::Simulate(transaction, sender_override) => {
+            match manager.simulate_transaction(
+                Transaction {
+                    from: transaction.from,
+                    to: transaction.to,
+                    amount: transaction.amount,
+                    timestamp: transaction.timestamp,
+                },
+                VerifyingKey::from_bytes(&transaction.public_key)
+                    .map_err(|e| anyhow!("Invalid public key: {}", e))?,
+                transaction.signature,
+                transaction.refund_of.as_deref(),
+                sender_override,
+            ) {
+                Ok(sender) => Ok(serde_json::json!({
+                    "valid": true,
+                    "sender_balance": sender.balance,
+                    "sender_selfchain_height": sender.height,
+                })),
+                Err(e) => Err(anyhow!("Error simulating transaction: {}", e)),
+            }
+        }
         RPCRequest::GetBalance(address) => {
             match manager.get_address_balance_and_selfchain_height(address) {
-                Ok((res, _)) => Ok(res.to_string()),
+                Ok((res, _)) => Ok(JsonValue::String(res.to_string())),
                 Err(e) => Err(anyhow!("Error getting balance: {}", e)),
             }
         }
+        RPCRequest::GetChainHeads(addresses) => match manager.chain_heads(&addresses) {
+            Ok(chain_heads) => Ok(chain_heads
+                .into_iter()
+                .map(|(address, head, height)| {
+                    serde_json::json!({
+                        "address": address.as_hex(),
+                        "head": hex::encode(head.0),
+                        "height": height,
+                    })
+                })
+                .collect()),
+            Err(e) => Err(anyhow!("Error getting chain heads: {}", e)),
+        },
     }
 }
 
 async fn handle_rpc_request(
     req: &JsonValue,
-    tx_queue_sender: mpsc::Sender<QueuedTransaction>,
-) -> Result<String, Box<dyn Error + Send + Sync>> {
+    tx_queue_sender: TransactionQueue,
+    drain_state: Arc<DrainState>,
+    ledgers: Arc<TransactionManagerRegistry>,
+    network_admin: Arc<NetworkAdmin>,
+) -> Result<JsonValue, Box<dyn Error + Send + Sync>> {
     info!("Handling request method: {:?}", req["method"]);
 
+    let ledger = match req.get("ledger") {
+        None => None,
+        Some(JsonValue::String(ledger)) => Some(ledger.as_str()),
+        Some(_) => return Err("Invalid ledger - expected str".into()),
+    };
+    let transaction_manager = ledgers.get(ledger)?;
+
     match req["method"].as_str() {
         Some("submitTransaction") => {
+            if drain_state.draining.load(Ordering::SeqCst) {
+                return Err("Node is draining, not accepting new transactions".into());
+            }
+
             let params = req["params"]
                 .as_array()
                 .ok_or("Invalid params - expected array")?;
@@ -228,6 +802,7 @@ async fn handle_rpc_request(
 
             // Queue the transaction
             let queued_tx = QueuedTransaction {
+                transaction_manager,
                 request: RPCRequest::Transfer(transaction_request),
                 response_sender,
             };
@@ -244,6 +819,43 @@ async fn handle_rpc_request(
                 Err(e) => Err(anyhow!("Failed to receive transaction result: {}", e).into()),
             }
         }
+        Some("simulateTransaction") => {
+            let params = req["params"]
+                .as_array()
+                .ok_or("Invalid params - expected array")?;
+
+            if params.is_empty() {
+                return Err("Empty params array".into());
+            }
+
+            let transaction_request: TransactionRequest =
+                serde_json::from_value(params[0].clone())?;
+            // Optional balance and height to validate against instead of the
+            // stored state of the sender
+            let sender_override: Option<SelfchainState> = match params.get(1) {
+                Some(sender_override) => serde_json::from_value(sender_override.clone())?,
+                None => None,
+            };
+
+            let (response_sender, response_receiver) = oneshot::channel();
+
+            let queued_tx = QueuedTransaction {
+                transaction_manager,
+                request: RPCRequest::Simulate(transaction_request, sender_override),
+                response_sender,
+            };
+
+            tx_queue_sender
+                .send(queued_tx)
+                .await
+                .map_err(|e| anyhow!("Failed to queue simulation: {}", e))?;
+
+            match response_receiver.await {
+                Ok(Ok(result)) => Ok(result),
+                Ok(Err(e)) => Err(anyhow!(e).into()),
+                Err(e) => Err(anyhow!("Failed to receive simulation result: {}", e).into()),
+            }
+        }
         Some("addressBalance") => {
             let params = req["params"]
                 .as_str()
@@ -255,6 +867,7 @@ async fn handle_rpc_request(
 
             // Create a special transaction request for balance query
             let queued_tx = QueuedTransaction {
+                transaction_manager,
                 request: RPCRequest::GetBalance(address),
                 response_sender,
             };
@@ -271,6 +884,125 @@ async fn handle_rpc_request(
                 Err(e) => Err(anyhow!("Failed to receive balance result: {}", e).into()),
             }
         }
+        Some("getChainHeads") => {
+            let addresses = req["params"]
+                .as_array()
+                .ok_or("Invalid params - expected array")?
+                .iter()
+                .map(|address| {
+                    address
+                        .as_str()
+                        .ok_or_else(|| anyhow!("Invalid address - expected str"))
+                        .and_then(Address::from_hex)
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let (response_sender, response_receiver) = oneshot::channel();
+
+            let queued_tx = QueuedTransaction {
+                transaction_manager,
+                request: RPCRequest::GetChainHeads(addresses),
+                response_sender,
+            };
+
+            tx_queue_sender
+                .send(queued_tx)
+                .await
+                .map_err(|e| anyhow!("Failed to queue chain heads request: {}", e))?;
+
+            match response_receiver.await {
+                Ok(Ok(result)) => Ok(result),
+                Ok(Err(e)) => Err(anyhow!(e).into()),
+                Err(e) => Err(anyhow!("Failed to receive chain heads result: {}", e).into()),
+            }
+        }
+        Some("drain") => {
+            network_admin.authorize(req)?;
+
+            if !NetworkAdmin::confirmed(req) {
+                return Ok(serde_json::json!({
+                    "dry_run": true,
+                    "draining": drain_state.draining.load(Ordering::SeqCst),
+                    "queued_transactions": tx_queue_sender.len(),
+                    "grace_period_secs": drain_state.grace_period.as_secs(),
+                }));
+            }
+
+            if drain_state.draining.swap(true, Ordering::SeqCst) {
+                return Ok("Node is already draining".into());
+            }
+
+            warn!("Draining node, refusing new transactions");
+            tokio::spawn(async move {
+                while !tx_queue_sender.is_empty() {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                info!(
+                    "Transaction queue drained, shutting down in {:?}",
+                    drain_state.grace_period
+                );
+                tokio::time::sleep(drain_state.grace_period).await;
+                drain_state.drained.notify_one();
+            });
+
+            Ok("Node is draining".into())
+        }
+        Some("listPeers") => {
+            network_admin.authorize(req)?;
+
+            let peers = network_admin.peers().await?;
+            Ok(serde_json::to_value(
+                peers
+                    .into_iter()
+                    .filter(|peer| peer.connected)
+                    .collect::<Vec<_>>(),
+            )?)
+        }
+        Some("networkStatus") => {
+            network_admin.authorize(req)?;
+
+            let peers = network_admin.peers().await?;
+            Ok(serde_json::json!({
+                "connected_peers": network_admin.status.connected_peers(),
+                "isolated": network_admin.status.is_isolated(),
+                "peers": peers,
+            }))
+        }
+        Some("disconnectPeer") => {
+            network_admin.authorize(req)?;
+
+            let peer_id: PeerId = req["params"]
+                .as_str()
+                .ok_or("Invalid params - expected str")?
+                .parse()?;
+
+            if !NetworkAdmin::confirmed(req) {
+                let connected = network_admin
+                    .peers()
+                    .await?
+                    .iter()
+                    .any(|peer| peer.connected && peer.peer_id == peer_id.to_string());
+                return Ok(serde_json::json!({
+                    "dry_run": true,
+                    "peer_id": peer_id.to_string(),
+                    "connected": connected,
+                }));
+            }
+
+            let (response_sender, response_receiver) = oneshot::channel();
+            network_admin
+                .commands
+                .send(NetworkCommand::DisconnectPeer(peer_id, response_sender))
+                .await
+                .map_err(|e| anyhow!("Failed to send disconnect peer command: {}", e))?;
+
+            let was_connected = response_receiver
+                .await
+                .map_err(|e| anyhow!("Failed to receive disconnect result: {}", e))?;
+            Ok(
+                serde_json::json!({ "peer_id": peer_id.to_string(), "was_connected": was_connected }),
+            )
+        }
         Some(method) => {
             error!("Unknown method called: {}", method);
             Err(format!("Unknown method: {}", method).into())
@@ -281,3 +1013,644 @@ async fn handle_rpc_request(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction_manager::DEFAULT_LEDGER;
+    use crate::GenesisArgs;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::path::Path;
+
+    const A: Address = Address([1; 32]);
+    const B: Address = Address([2; 32]);
+
+    fn ledger(dir: &Path, balances: &[(Address, u64)]) -> TransactionManager {
+        let manager = TransactionManager::new(dir).unwrap();
+        manager
+            .load_genesis_transactions(GenesisArgs {
+                balances: balances
+                    .iter()
+                    .map(|(address, amount)| (address.as_hex(), *amount))
+                    .collect(),
+            })
+            .unwrap();
+        manager
+    }
+
+    /// Signed transfer, as sent in the params of `submitTransaction`
+    fn transfer_json(from: Address, to: Address, amount: u64) -> JsonValue {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let transaction = Transaction::new(from, to, amount).unwrap();
+        let id = transaction.calculate_id(None).unwrap();
+        let signature = signing_key.sign(&id);
+        serde_json::json!({
+            "from": from.as_hex(),
+            "to": to.as_hex(),
+            "amount": amount,
+            "public_key": hex::encode(signing_key.verifying_key().as_bytes()),
+            "signature": {
+                "R": hex::encode(signature.r_bytes()),
+                "s": hex::encode(signature.s_bytes()),
+            },
+            "timestamp": transaction.timestamp,
+            "id": hex::encode(id),
+        })
+    }
+
+    fn transfer_request(from: Address, to: Address, amount: u64) -> TransactionRequest {
+        serde_json::from_value(transfer_json(from, to, amount)).unwrap()
+    }
+
+    fn queued(
+        manager: &Arc<TransactionManager>,
+        request: RPCRequest,
+    ) -> (
+        QueuedTransaction,
+        oneshot::Receiver<Result<JsonValue, String>>,
+    ) {
+        let (response_sender, response_receiver) = oneshot::channel();
+        let queued_tx = QueuedTransaction {
+            transaction_manager: Arc::clone(manager),
+            request,
+            response_sender,
+        };
+        (queued_tx, response_receiver)
+    }
+
+    fn start_workers(workers: usize) -> TransactionQueue {
+        let (queue, receivers) = TransactionQueue::new(workers);
+        for receiver in receivers {
+            tokio::spawn(process_transaction_queue(receiver));
+        }
+        queue
+    }
+
+    fn network_admin() -> Arc<NetworkAdmin> {
+        let (commands, _) = mpsc::channel(1);
+        Arc::new(NetworkAdmin {
+            commands,
+            status: Arc::new(NetworkStatus::new(None)),
+            token: Some("secret".to_string()),
+        })
+    }
+
+    fn drain_state() -> Arc<DrainState> {
+        Arc::new(DrainState {
+            draining: AtomicBool::new(false),
+            grace_period: Duration::ZERO,
+            drained: Notify::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn drain_refuses_new_transactions_and_completes_queued_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ledgers = TransactionManagerRegistry::new();
+        let manager = ledgers
+            .insert(DEFAULT_LEDGER, ledger(dir.path(), &[(A, 1000)]))
+            .unwrap();
+        let ledgers = Arc::new(ledgers);
+        let network_admin = network_admin();
+        let drain_state = drain_state();
+        // Workers are started once the node is draining, so the first transfer
+        // is still queued
+        let (queue, receivers) = TransactionQueue::new(1);
+
+        let call = |req: JsonValue| {
+            let (queue, drain_state, ledgers, network_admin) = (
+                queue.clone(),
+                Arc::clone(&drain_state),
+                Arc::clone(&ledgers),
+                Arc::clone(&network_admin),
+            );
+            tokio::spawn(async move {
+                handle_rpc_request(&req, queue, drain_state, ledgers, network_admin)
+                    .await
+                    .map_err(|e| e.to_string())
+            })
+        };
+        let submit = || {
+            serde_json::json!({
+                "method": "submitTransaction",
+                "params": [transfer_json(A, B, 10)],
+            })
+        };
+
+        let queued = call(submit());
+        while queue.is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let unauthorized = call(serde_json::json!({ "method": "drain" }))
+            .await
+            .unwrap();
+        assert!(unauthorized.is_err());
+        let dry_run = call(serde_json::json!({ "method": "drain", "auth": "secret" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dry_run["dry_run"], true);
+        assert_eq!(dry_run["queued_transactions"], 1);
+        assert!(!drain_state.draining.load(Ordering::SeqCst));
+        call(serde_json::json!({ "method": "drain", "auth": "secret", "confirm": true }))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let refused = call(submit()).await.unwrap().unwrap_err();
+        assert!(refused.contains("draining"));
+
+        for receiver in receivers {
+            tokio::spawn(process_transaction_queue(receiver));
+        }
+        queued.await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), drain_state.drained.notified())
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.get_address_balance_and_selfchain_height(B).unwrap(),
+            (10, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_peer_only_acts_once_confirmed() {
+        let (commands, mut received) = mpsc::channel(1);
+        let network_admin = Arc::new(NetworkAdmin {
+            commands,
+            status: Arc::new(NetworkStatus::new(None)),
+            token: Some("secret".to_string()),
+        });
+        let peer_id = PeerId::random();
+        let disconnected = tokio::spawn(async move {
+            let mut disconnected = Vec::new();
+            while let Some(command) = received.recv().await {
+                match command {
+                    NetworkCommand::Peers(reply) => reply.send(Vec::new()).unwrap(),
+                    NetworkCommand::DisconnectPeer(peer_id, reply) => {
+                        disconnected.push(peer_id);
+                        reply.send(true).unwrap();
+                    }
+                }
+            }
+            disconnected
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let mut ledgers = TransactionManagerRegistry::new();
+        ledgers
+            .insert(DEFAULT_LEDGER, ledger(dir.path(), &[(A, 1000)]))
+            .unwrap();
+        let ledgers = Arc::new(ledgers);
+        let (queue, _receivers) = TransactionQueue::new(1);
+        let call = |req: JsonValue| {
+            let (queue, ledgers, network_admin) = (
+                queue.clone(),
+                Arc::clone(&ledgers),
+                Arc::clone(&network_admin),
+            );
+            async move {
+                handle_rpc_request(&req, queue, drain_state(), ledgers, network_admin)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let dry_run = call(serde_json::json!({
+            "method": "disconnectPeer",
+            "auth": "secret",
+            "params": peer_id.to_string(),
+        }))
+        .await;
+        assert_eq!(dry_run["dry_run"], true);
+        let result = call(serde_json::json!({
+            "method": "disconnectPeer",
+            "auth": "secret",
+            "params": peer_id.to_string(),
+            "confirm": true,
+        }))
+        .await;
+        assert_eq!(result["was_connected"], true);
+
+        drop(network_admin);
+        assert_eq!(disconnected.await.unwrap(), vec![peer_id]);
+    }
+
+    #[tokio::test]
+    async fn transactions_of_a_sender_are_processed_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(ledger(dir.path(), &[(A, 1000)]));
+        let queue = start_workers(4);
+
+        let mut responses = Vec::new();
+        for amount in 1..=20 {
+            let (queued_tx, response) = queued(
+                &manager,
+                RPCRequest::Transfer(transfer_request(A, B, amount)),
+            );
+            queue.send(queued_tx).await.unwrap();
+            responses.push(response);
+        }
+        for response in responses {
+            response.await.unwrap().unwrap();
+        }
+
+        for amount in 1..=20 {
+            let transaction = manager
+                .get_transaction(format!("{}:{}", A.as_hex(), amount))
+                .unwrap();
+            assert_eq!(transaction.amount, amount as u64);
+        }
+    }
+
+    /// Sends a transfer from `busy` while its address lock is held, then returns
+    /// whether a transfer from `other` completes in the meantime
+    async fn completes_while_sender_is_busy(workers: usize, busy: Address, other: Address) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(ledger(dir.path(), &[(busy, 1000), (other, 1000)]));
+        let queue = start_workers(workers);
+
+        let (locked_sender, locked) = std::sync::mpsc::channel();
+        let (release_sender, release) = std::sync::mpsc::channel::<()>();
+        let lock_holder = {
+            let manager = Arc::clone(&manager);
+            std::thread::spawn(move || {
+                let locks = manager.address_locks(&[busy]);
+                let _guard = locks[0].lock().unwrap();
+                locked_sender.send(()).unwrap();
+                let _ = release.recv();
+            })
+        };
+        locked.recv().unwrap();
+
+        let (queued_tx, busy_response) =
+            queued(&manager, RPCRequest::Transfer(transfer_request(busy, B, 1)));
+        queue.send(queued_tx).await.unwrap();
+        let (queued_tx, other_response) = queued(
+            &manager,
+            RPCRequest::Transfer(transfer_request(other, B, 1)),
+        );
+        queue.send(queued_tx).await.unwrap();
+
+        let completed = tokio::time::timeout(Duration::from_millis(500), other_response)
+            .await
+            .is_ok();
+
+        release_sender.send(()).unwrap();
+        lock_holder.join().unwrap();
+        busy_response.await.unwrap().unwrap();
+        completed
+    }
+
+    #[tokio::test]
+    async fn independent_senders_are_processed_concurrently() {
+        let workers = 4;
+        let (queue, _receivers) = TransactionQueue::new(workers);
+        // A sender handled by another worker than A
+        let other = (3..=u8::MAX)
+            .map(|byte| Address([byte; 32]))
+            .find(|address| queue.worker_of(Some(*address)) != queue.worker_of(Some(A)))
+            .unwrap();
+
+        // A single worker is stuck behind the busy sender
+        assert!(!completes_while_sender_is_busy(1, A, other).await);
+        assert!(completes_while_sender_is_busy(workers, A, other).await);
+    }
+
+    fn rpc_state(dir: &Path, max_request_size: usize) -> RpcState {
+        let mut ledgers = TransactionManagerRegistry::new();
+        ledgers
+            .insert(DEFAULT_LEDGER, ledger(dir, &[(A, 1000)]))
+            .unwrap();
+        RpcState {
+            tx_queue_sender: start_workers(1),
+            drain_state: drain_state(),
+            ledgers: Arc::new(ledgers),
+            network_status: Arc::new(NetworkStatus::new(None)),
+            network_admin: network_admin(),
+            gzip_threshold: usize::MAX,
+            max_request_size,
+        }
+    }
+
+    /// Sends the request over a connection, split in `chunks`, and returns the
+    /// raw response
+    async fn http_exchange(state: RpcState, chunks: &[&[u8]]) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let connection = tokio::spawn(handle_connection(server, state));
+
+        for chunk in chunks {
+            client.write_all(chunk).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        connection.await.unwrap();
+        response
+    }
+
+    fn post(body: &str) -> String {
+        format!(
+            "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    fn balance_request(id: Option<u64>) -> JsonValue {
+        let mut request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "addressBalance",
+            "params": A.as_hex(),
+        });
+        if let Some(id) = id {
+            request["id"] = serde_json::json!(id);
+        }
+        request
+    }
+
+    fn response_body(response: &str) -> JsonValue {
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn body_split_across_reads_is_read_up_to_its_content_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = post(&balance_request(Some(1)).to_string());
+        let (headers, body) = request.split_at(request.find("\r\n\r\n").unwrap() + 4);
+        let (body_start, body_end) = body.split_at(body.len() / 2);
+
+        let response = http_exchange(
+            rpc_state(dir.path(), 1 << 20),
+            &[
+                headers.as_bytes(),
+                body_start.as_bytes(),
+                body_end.as_bytes(),
+            ],
+        )
+        .await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert_eq!(response_body(&response)["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn notification_gets_no_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = post(&balance_request(None).to_string());
+
+        let response = http_exchange(rpc_state(dir.path(), 1 << 20), &[request.as_bytes()]).await;
+
+        assert_eq!(response, "HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn batch_is_answered_without_its_notifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let batch = serde_json::json!([
+            balance_request(Some(1)),
+            balance_request(None),
+            balance_request(Some(2)),
+        ]);
+        let request = post(&batch.to_string());
+
+        let response = http_exchange(rpc_state(dir.path(), 1 << 20), &[request.as_bytes()]).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        let ids: Vec<JsonValue> = response_body(&response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|response| response["id"].clone())
+            .collect();
+        assert_eq!(ids, vec![serde_json::json!(1), serde_json::json!(2)]);
+    }
+
+    #[tokio::test]
+    async fn request_over_the_maximum_size_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = post(&balance_request(Some(1)).to_string());
+
+        let response = http_exchange(
+            rpc_state(dir.path(), request.len() - 1),
+            &[request.as_bytes()],
+        )
+        .await;
+
+        assert!(
+            response.starts_with("HTTP/1.1 413 Payload Too Large"),
+            "{}",
+            response
+        );
+    }
+
+    /// Splits a response into its headers and body
+    fn split_response(response: &[u8]) -> (String, &[u8]) {
+        let headers_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        (
+            String::from_utf8_lossy(&response[..headers_end]).into_owned(),
+            &response[headers_end + 4..],
+        )
+    }
+
+    #[test]
+    fn gzip_with_a_zero_q_value_is_refused() {
+        for refused in ["gzip;q=0", "deflate, gzip; q=0.000", "identity"] {
+            let headers = format!("POST / HTTP/1.1\r\nAccept-Encoding: {}", refused);
+            assert!(!accepts_gzip(&headers), "{}", refused);
+        }
+        for accepted in ["gzip", "GZIP;q=0.5", "deflate;q=0, gzip;q=1"] {
+            let headers = format!("POST / HTTP/1.1\r\nAccept-Encoding: {}", accepted);
+            assert!(accepts_gzip(&headers), "{}", accepted);
+        }
+    }
+
+    #[test]
+    fn large_response_is_gzipped_for_clients_accepting_it() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let body = serde_json::json!({ "result": "x".repeat(4096) }).to_string();
+        assert!(accepts_gzip(
+            "POST / HTTP/1.1\r\nAccept-Encoding: deflate, gzip;q=0.8"
+        ));
+
+        let response = build_http_response("200 OK", &body, true, 1024);
+        let (headers, compressed) = split_response(&response);
+        assert!(headers.contains("Content-Encoding: gzip"));
+        assert!(headers.contains(&format!("Content-Length: {}", compressed.len())));
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(compressed)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn small_responses_and_other_clients_are_sent_uncompressed() {
+        assert!(!accepts_gzip("POST / HTTP/1.1\r\nAccept-Encoding: br"));
+
+        let body = serde_json::json!({ "result": "x".repeat(4096) }).to_string();
+        for (body, use_gzip) in [("{}", true), (body.as_str(), false)] {
+            let response = build_http_response("200 OK", body, use_gzip, 1024);
+            let (headers, sent_body) = split_response(&response);
+            assert!(!headers.contains("Content-Encoding"));
+            assert_eq!(sent_body, body.as_bytes());
+        }
+    }
+
+    /// Runs a request against a ledger funding A
+    async fn call(dir: &Path, request: JsonValue) -> (&'static str, Option<JsonValue>) {
+        let state = rpc_state(dir, 1 << 20);
+        process_rpc_request(
+            request,
+            state.tx_queue_sender,
+            state.drain_state,
+            state.ledgers,
+            state.network_admin,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn string_number_and_null_ids_are_echoed() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for id in [
+            serde_json::json!(7),
+            serde_json::json!(1.5),
+            serde_json::json!("request-7"),
+            JsonValue::Null,
+        ] {
+            let mut request = balance_request(None);
+            request["id"] = id.clone();
+
+            let (status, response) = call(dir.path(), request).await;
+            let response = response.unwrap();
+            assert_eq!(status, "200 OK");
+            assert_eq!(response["id"], id);
+            assert_eq!(response["result"], "1000");
+        }
+    }
+
+    #[tokio::test]
+    async fn object_and_array_ids_are_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for id in [serde_json::json!({ "n": 1 }), serde_json::json!([1])] {
+            let mut request = balance_request(None);
+            request["id"] = id;
+
+            let (status, response) = call(dir.path(), request).await;
+            let response = response.unwrap();
+            assert_eq!(status, "400 Bad Request");
+            assert_eq!(response["error"]["code"], -32600);
+            assert_eq!(response["id"], JsonValue::Null);
+        }
+    }
+
+    #[tokio::test]
+    async fn unparseable_request_is_answered_with_a_null_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = post(r#"{"jsonrpc": "2.0", "id": 1"#);
+
+        let response = http_exchange(rpc_state(dir.path(), 1 << 20), &[request.as_bytes()]).await;
+
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "{}",
+            response
+        );
+        let response = response_body(&response);
+        assert_eq!(response["error"]["code"], -32700);
+        assert_eq!(response["id"], JsonValue::Null);
+    }
+
+    #[tokio::test]
+    async fn ledgers_keep_their_balances_apart() {
+        let (default_dir, other_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut ledgers = TransactionManagerRegistry::new();
+        let default_ledger = ledgers
+            .insert(DEFAULT_LEDGER, ledger(default_dir.path(), &[(A, 1000)]))
+            .unwrap();
+        let other_ledger = ledgers
+            .insert("other", ledger(other_dir.path(), &[(A, 50), (B, 5)]))
+            .unwrap();
+        let ledgers = Arc::new(ledgers);
+        let queue = start_workers(1);
+
+        let call = |req: JsonValue| {
+            let (queue, ledgers) = (queue.clone(), Arc::clone(&ledgers));
+            async move {
+                handle_rpc_request(&req, queue, drain_state(), ledgers, network_admin())
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        };
+        let balance_of = |address: Address, ledger: Option<&str>| {
+            let mut req = serde_json::json!({
+                "method": "addressBalance",
+                "params": address.as_hex(),
+            });
+            if let Some(ledger) = ledger {
+                req["ledger"] = serde_json::json!(ledger);
+            }
+            req
+        };
+
+        call(serde_json::json!({
+            "method": "submitTransaction",
+            "params": [transfer_json(A, B, 20)],
+            "ledger": "other",
+        }))
+        .await
+        .unwrap();
+
+        assert_eq!(call(balance_of(A, None)).await.unwrap(), "1000");
+        assert_eq!(call(balance_of(B, None)).await.unwrap(), "0");
+        assert_eq!(call(balance_of(A, Some("other"))).await.unwrap(), "30");
+        assert_eq!(call(balance_of(B, Some("other"))).await.unwrap(), "25");
+        assert_eq!(
+            default_ledger
+                .get_address_balance_and_selfchain_height(B)
+                .unwrap(),
+            (0, 0)
+        );
+        assert_eq!(
+            other_ledger
+                .get_address_balance_and_selfchain_height(B)
+                .unwrap(),
+            (25, 2)
+        );
+
+        let unknown = call(balance_of(A, Some("missing"))).await.unwrap_err();
+        assert!(unknown.contains("Unknown ledger: missing"));
+    }
+
+    #[tokio::test]
+    async fn health_of_an_isolated_node_is_unavailable() {
+        let dir = tempfile::tempdir().unwrap();
+        let network_status = Arc::new(NetworkStatus::new(Some(Duration::ZERO)));
+        network_status.check_isolation();
+        let health = || {
+            let mut state = rpc_state(dir.path(), 1 << 20);
+            state.network_status = Arc::clone(&network_status);
+            http_exchange(state, &[b"GET /health HTTP/1.1\r\n\r\n"])
+        };
+
+        let response = health().await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(response.contains(r#""status":"isolated""#));
+
+        network_status.peer_connected();
+        let response = health().await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(r#""connected_peers":1"#));
+    }
+}
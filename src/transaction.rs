@@ -1,4 +1,4 @@
-use crate::address::Address;
+use crate::address::{decode_hex_32, Address};
 use anyhow::Result;
 use chrono::Utc;
 use ed25519_dalek::Signature;
@@ -23,6 +23,9 @@ pub struct TransactionRequest {
     pub timestamp: i64,
     #[serde(deserialize_with = "deserialize_hex_to_tx_id")]
     pub id: TransactionHash,
+    /// Id of the transaction this one refunds, as returned by `submitTransaction`
+    #[serde(default)]
+    pub refund_of: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,6 +35,10 @@ struct SignatureComponents {
     s: String,
 }
 
+fn decode_signature_component(name: &str, hex_str: &str) -> Result<[u8; 32], String> {
+    decode_hex_32(hex_str).map_err(|e| format!("Invalid {}: {}", name, e))
+}
+
 fn deserialize_signature<'de, D>(deserializer: D) -> Result<Signature, D::Error>
 where
     D: Deserializer<'de>,
@@ -39,20 +46,13 @@ where
     let components = SignatureComponents::deserialize(deserializer)?;
 
     #[allow(non_snake_case)]
-    let R_bytes = hex::decode(components.R).map_err(de::Error::custom)?;
-    #[allow(non_snake_case)]
-    let mut R_array = [0u8; 32];
-    if R_array.len() != 32 {
-        return Err(de::Error::custom("Invalid length for R"));
-    }
-    R_array.copy_from_slice(&R_bytes);
+    let R_array = decode_signature_component("R", &components.R).map_err(de::Error::custom)?;
+    let s_array = decode_signature_component("s", &components.s).map_err(de::Error::custom)?;
 
-    let s_bytes = hex::decode(components.s).map_err(de::Error::custom)?;
-    let mut s_array = [0u8; 32];
-    if s_array.len() != 32 {
-        return Err(de::Error::custom("Invalid length for s"));
+    // An s at or above the curve order is refused by verify_strict
+    if s_array == [0u8; 32] {
+        return Err(de::Error::custom("s must not be zero"));
     }
-    s_array.copy_from_slice(&s_bytes);
 
     // Combine R and s into a single 64-byte array
     let mut sig_bytes = [0u8; 64];
@@ -67,14 +67,7 @@ where
     D: Deserializer<'de>,
 {
     let s: String = Deserialize::deserialize(deserializer)?;
-    let s = s.trim_start_matches("0x");
-    let bytes = hex::decode(s).map_err(de::Error::custom)?;
-    let mut array = [0u8; 32];
-    if bytes.len() != 32 {
-        return Err(de::Error::custom("Invalid length for byte array"));
-    }
-    array.copy_from_slice(&bytes);
-    Ok(array)
+    decode_hex_32(&s).map_err(de::Error::custom)
 }
 
 fn deserialize_hex_to_address<'de, D>(deserializer: D) -> Result<Address, D::Error>
@@ -82,28 +75,17 @@ where
     D: Deserializer<'de>,
 {
     let s: String = Deserialize::deserialize(deserializer)?;
-    let s = s.trim_start_matches("0x");
-    let bytes = hex::decode(s).map_err(de::Error::custom)?;
-    let mut array = [0u8; 32];
-    if bytes.len() != 32 {
-        return Err(de::Error::custom("Invalid length for byte array"));
-    }
-    array.copy_from_slice(&bytes);
-    Ok(Address::from(array))
+    Address::from_hex(&s).map_err(de::Error::custom)
 }
+
 fn deserialize_hex_to_tx_id<'de, D>(deserializer: D) -> Result<TransactionHash, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s: String = Deserialize::deserialize(deserializer)?;
-    let s = s.trim_start_matches("0x");
-    let bytes = hex::decode(s).map_err(de::Error::custom)?;
-    let mut array = [0u8; 32];
-    if bytes.len() != 32 {
-        return Err(de::Error::custom("Invalid length for byte array"));
-    }
-    array.copy_from_slice(&bytes);
-    Ok(TransactionHash(array))
+    Ok(TransactionHash(
+        decode_hex_32(&s).map_err(de::Error::custom)?,
+    ))
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -125,15 +107,122 @@ impl Transaction {
         })
     }
 
-    pub fn calculate_id(&self) -> Result<[u8; 32]> {
+    /// Id signed by the sender. A refund also commits to the id of the
+    /// transaction it refunds, so it can't be added or changed by a relay.
+    pub fn calculate_id(&self, refund_of: Option<&str>) -> Result<[u8; 32]> {
         let mut hasher = Sha256::new();
         hasher.update(self.amount.to_be_bytes());
         hasher.update(self.from);
         hasher.update(self.to);
         hasher.update(self.timestamp.to_be_bytes());
+        // Tagged and length prefixed, so no refund hashes like a plain transfer
+        if let Some(refund_of) = refund_of {
+            hasher.update(b"refund_of");
+            hasher.update((refund_of.len() as u64).to_be_bytes());
+            hasher.update(refund_of.as_bytes());
+        }
 
         let hash = &hasher.finalize()[..];
         let id: [u8; 32] = hash.try_into().expect("Wrong length");
         Ok(id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Fields of a valid request, as sent in `submitTransaction`
+    fn request_json() -> serde_json::Value {
+        json!({
+            "from": hex::encode([1; 32]),
+            "to": hex::encode([2; 32]),
+            "amount": 10,
+            "public_key": hex::encode([3; 32]),
+            "signature": { "R": hex::encode([5; 32]), "s": hex::encode([1; 32]) },
+            "timestamp": 0,
+            "id": hex::encode([4; 32]),
+        })
+    }
+
+    #[allow(non_snake_case)]
+    fn request_with_signature(R: &str, s: &str) -> serde_json::Result<TransactionRequest> {
+        let mut request = request_json();
+        request["signature"] = json!({ "R": R, "s": s });
+        serde_json::from_value(request)
+    }
+
+    fn signature_error(r: &str, s: &str) -> String {
+        request_with_signature(r, s).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn refund_id_differs_from_the_plain_transfer_id() {
+        let transaction = Transaction {
+            from: Address([1; 32]),
+            to: Address([2; 32]),
+            amount: 10,
+            timestamp: 0,
+        };
+        let plain_id = transaction.calculate_id(None).unwrap();
+
+        assert_ne!(transaction.calculate_id(Some("")).unwrap(), plain_id);
+        assert_ne!(
+            transaction.calculate_id(Some("a")).unwrap(),
+            transaction.calculate_id(Some("b")).unwrap()
+        );
+    }
+
+    #[test]
+    fn valid_signature_is_accepted() {
+        assert!(request_with_signature(&hex::encode([5; 32]), &hex::encode([1; 32])).is_ok());
+    }
+
+    #[test]
+    fn wrong_length_signature_components_are_rejected() {
+        let error = signature_error(&hex::encode([5; 31]), &hex::encode([1; 32]));
+        assert!(
+            error.contains("Invalid R: Invalid length: expected 32 bytes, got 31"),
+            "{}",
+            error
+        );
+
+        let error = signature_error(&hex::encode([5; 32]), &hex::encode([1; 33]));
+        assert!(
+            error.contains("Invalid s: Invalid length: expected 32 bytes, got 33"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn empty_signature_components_are_rejected() {
+        let error = signature_error("", &hex::encode([1; 32]));
+        assert!(error.contains("Invalid R: Empty hex string"), "{}", error);
+
+        let error = signature_error(&hex::encode([5; 32]), " 0x ");
+        assert!(error.contains("Invalid s: Empty hex string"), "{}", error);
+    }
+
+    #[test]
+    fn empty_hex_fields_are_rejected() {
+        for field in ["from", "to", "public_key", "id"] {
+            for input in ["", "  ", "0x"] {
+                let mut request = request_json();
+                request[field] = json!(input);
+
+                let error = serde_json::from_value::<TransactionRequest>(request)
+                    .unwrap_err()
+                    .to_string();
+                assert!(error.contains("Empty hex string"), "{}: {}", field, error);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_s_is_rejected() {
+        let error = signature_error(&hex::encode([5; 32]), &hex::encode([0; 32]));
+        assert!(error.contains("s must not be zero"), "{}", error);
+    }
+}
@@ -1,32 +1,246 @@
 use anyhow::{anyhow, Result};
 use ed25519_dalek::Signature;
+use ed25519_dalek::Signer;
+use ed25519_dalek::SigningKey;
 use ed25519_dalek::VerifyingKey;
 use lmdb::Cursor;
 use lmdb::Database;
 use lmdb::Environment;
+use lmdb::RwTransaction;
 use lmdb::Transaction as LmdbTransaction;
-use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tracing::info;
 
 use crate::address::{Address, ZERO_ADDRESS};
 use crate::transaction::{Transaction, TransactionHash};
 use crate::GenesisArgs;
-use crate::DB_NAME;
-
-static LMDB_ENV: Lazy<Arc<Environment>> = Lazy::new(|| {
-    std::fs::create_dir_all(DB_NAME).expect("Failed to create transaction_db directory");
-    Arc::new(
-        lmdb::Environment::new()
-            .set_max_dbs(1)
-            .set_map_size(10 * 1024 * 1024)
-            .set_max_readers(126)
-            .open(Path::new(DB_NAME))
-            .expect("Failed to create LMDB environment"),
+
+/// Name of the ledger used when a request doesn't specify one
+pub const DEFAULT_LEDGER: &str = "default";
+
+/// Directory of the default ledger inside the data directory of a node
+pub const DB_NAME: &str = "transaction_db";
+
+/// Version of the storage layout written by this build
+const SCHEMA_VERSION: u32 = 5;
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// Named database holding the schema version. Named databases are themselves
+/// keys of the main database.
+const META_DB_NAME: &str = "meta";
+/// Named database linking each refunded transaction to its refunds
+const REFUNDS_DB_NAME: &str = "refunds";
+/// Named database holding, per address, the balance and height of the pruned
+/// start of its selfchain
+const CHECKPOINTS_DB_NAME: &str = "checkpoints";
+
+/// Databases of an environment, as seen by the migrations
+struct Databases {
+    main: Database,
+    refunds: Database,
+    checkpoints: Database,
+}
+
+/// Upgrades the storage layout by one version: `MIGRATIONS[n]` migrates a
+/// database from version `n` to `n + 1`
+type Migration = fn(&mut RwTransaction, &Databases) -> Result<()>;
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    migrate_unversioned,
+    migrate_refunds,
+    migrate_checkpoints,
+    migrate_checkpoint_heads,
+    migrate_refund_links,
+];
+
+/// Databases written before the schema version was stored share the layout of
+/// version 1, so only the version needs to be recorded
+fn migrate_unversioned(_txn: &mut RwTransaction, _dbs: &Databases) -> Result<()> {
+    Ok(())
+}
+
+/// Version 2 adds the refunds database. It is created on open and starts empty,
+/// as no refund predates it.
+fn migrate_refunds(_txn: &mut RwTransaction, _dbs: &Databases) -> Result<()> {
+    Ok(())
+}
+
+/// Version 3 adds the checkpoints database. It starts empty, as nothing was
+/// pruned before it.
+fn migrate_checkpoints(_txn: &mut RwTransaction, _dbs: &Databases) -> Result<()> {
+    Ok(())
+}
+
+/// Version 4 stores the head of the checkpointed records along with their
+/// balance and height. The heads of pruned records are gone, but they are only
+/// read when no record follows the checkpoint, which pruning never leaves.
+fn migrate_checkpoint_heads(txn: &mut RwTransaction, dbs: &Databases) -> Result<()> {
+    let checkpoints = {
+        let mut cursor = txn
+            .open_ro_cursor(dbs.checkpoints)
+            .map_err(|e| anyhow!("Failed to create cursor: {}", e))?;
+        cursor
+            .iter()
+            .map(|(key, value)| {
+                let state: SelfchainState = bincode::deserialize(value)
+                    .map_err(|e| anyhow!("Failed to deserialize checkpoint: {}", e))?;
+                Ok((key.to_vec(), state))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    for (key, state) in checkpoints {
+        let checkpoint = Checkpoint {
+            state,
+            head: TransactionHash::default(),
+        };
+        let serialized_checkpoint = bincode::serialize(&checkpoint)
+            .map_err(|e| anyhow!("Failed to serialize checkpoint: {}", e))?;
+        txn.put(
+            dbs.checkpoints,
+            &key,
+            &serialized_checkpoint,
+            lmdb::WriteFlags::empty(),
+        )
+        .map_err(|e| anyhow!("Failed to put checkpoint in database: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Version 5 stores the id a refund refunds along with it, so that its signed id
+/// can be hashed again. Refunds are found through the refunds database, which
+/// holds the recipient's copy of each refund, and their sender's copy is the
+/// record with the same transfer.
+fn migrate_refund_links(txn: &mut RwTransaction, dbs: &Databases) -> Result<()> {
+    let mut refunded_ids: HashMap<Vec<u8>, String> = HashMap::new();
+    let refunds = {
+        let mut cursor = txn
+            .open_ro_cursor(dbs.refunds)
+            .map_err(|e| anyhow!("Failed to create cursor: {}", e))?;
+        cursor
+            .iter()
+            .map(|(key, value)| {
+                let original_id = String::from_utf8(key.to_vec())
+                    .map_err(|e| anyhow!("Invalid key in refunds database: {}", e))?;
+                let record: RefundRecord = bincode::deserialize(value)
+                    .map_err(|e| anyhow!("Failed to deserialize refund record: {}", e))?;
+                Ok((original_id, record.refund_ids))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    for (original_id, refund_ids) in refunds {
+        for refund_id in refund_ids {
+            let transaction: Transaction = match txn.get(dbs.main, &refund_id) {
+                Ok(bytes) => bincode::deserialize(bytes)
+                    .map_err(|e| anyhow!("Failed to deserialize transaction: {}", e))?,
+                // Pruned along with the other copy, which is older
+                Err(lmdb::Error::NotFound) => continue,
+                Err(e) => return Err(anyhow!("Failed to get transaction: {}", e)),
+            };
+            let serialized_transaction = bincode::serialize(&transaction)
+                .map_err(|e| anyhow!("Failed to serialize transaction: {}", e))?;
+            refunded_ids.insert(serialized_transaction, original_id.clone());
+        }
+    }
+
+    let records = {
+        let mut cursor = txn
+            .open_ro_cursor(dbs.main)
+            .map_err(|e| anyhow!("Failed to create cursor: {}", e))?;
+        let mut records = Vec::new();
+        for (key, value) in cursor.iter() {
+            if [META_DB_NAME, REFUNDS_DB_NAME, CHECKPOINTS_DB_NAME]
+                .iter()
+                .any(|name| name.as_bytes() == key)
+            {
+                continue;
+            }
+            let transaction: Transaction = bincode::deserialize(value)
+                .map_err(|e| anyhow!("Failed to deserialize transaction: {}", e))?;
+            // Genesis records keep their own layout
+            if transaction.from != ZERO_ADDRESS {
+                records.push((key.to_vec(), transaction));
+            }
+        }
+        records
+    };
+    for (key, transaction) in records {
+        let serialized_transaction = bincode::serialize(&transaction)
+            .map_err(|e| anyhow!("Failed to serialize transaction: {}", e))?;
+        let stored_transaction = StoredTransaction {
+            transaction,
+            refund_of: refunded_ids.get(&serialized_transaction).cloned(),
+        };
+        let serialized_stored_transaction = bincode::serialize(&stored_transaction)
+            .map_err(|e| anyhow!("Failed to serialize transaction: {}", e))?;
+        txn.put(
+            dbs.main,
+            &key,
+            &serialized_stored_transaction,
+            lmdb::WriteFlags::empty(),
+        )
+        .map_err(|e| anyhow!("Failed to put transaction in database: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Applies the migrations from the stored schema version up to the current one.
+/// A database without a version predates versioning and is at version 0.
+/// Schema version of the database, 0 before versions were stored
+fn stored_schema_version<T: LmdbTransaction>(txn: &T, meta_db: Database) -> Result<u32> {
+    match txn.get(meta_db, &SCHEMA_VERSION_KEY) {
+        Ok(bytes) => {
+            Ok(u32::from_be_bytes(bytes.try_into().map_err(|_| {
+                anyhow!("Invalid schema version in database")
+            })?))
+        }
+        Err(lmdb::Error::NotFound) => Ok(0),
+        Err(e) => Err(anyhow!("Failed to read schema version: {}", e)),
+    }
+}
+
+fn run_migrations(env: &Environment, dbs: &Databases, meta_db: Database) -> Result<()> {
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
+
+    let stored_version = stored_schema_version(&txn, meta_db)?;
+    if stored_version > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "Database schema version {} is newer than the supported version {}",
+            stored_version,
+            SCHEMA_VERSION
+        ));
+    }
+    if stored_version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    for version in stored_version..SCHEMA_VERSION {
+        info!(
+            "Migrating database from schema version {} to {}",
+            version,
+            version + 1
+        );
+        MIGRATIONS[version as usize](&mut txn, dbs)?;
+    }
+
+    txn.put(
+        meta_db,
+        &SCHEMA_VERSION_KEY,
+        &SCHEMA_VERSION.to_be_bytes(),
+        lmdb::WriteFlags::empty(),
     )
-});
+    .map_err(|e| anyhow!("Failed to store schema version: {}", e))?;
+    txn.commit()
+        .map_err(|e| anyhow!("Failed to commit migrations: {}", e))?;
+
+    Ok(())
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 enum TransactionStatus {
@@ -35,6 +249,22 @@ enum TransactionStatus {
     Invalid,
 }
 
+/// Refunds of a transaction, stored under the id of the refunded transaction
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct RefundRecord {
+    refunded_amount: u64,
+    refund_ids: Vec<String>,
+}
+
+/// Record of a transaction added to the selfchains of its sender and recipient.
+/// The transfer comes first, so the record also reads as a [`Transaction`].
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StoredTransaction {
+    transaction: Transaction,
+    /// Id of the transaction it refunds, which is part of its signed id
+    refund_of: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct TransactionRecord {
     transaction: Transaction,
@@ -43,22 +273,238 @@ struct TransactionRecord {
     signature: Signature,
 }
 
+/// State of an address selfchain in a snapshot
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub address: Address,
+    pub balance: u64,
+    /// Number of records of the selfchain covered by the snapshot. The
+    /// selfchain is extended from this height once the snapshot is loaded.
+    pub height: u32,
+    pub head: TransactionHash,
+}
+
+/// State of every known address at a point in time, signed by the node that
+/// produced it so that a new node can bootstrap from it and then only sync the
+/// transactions past each selfchain height, instead of replaying the whole
+/// history
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub genesis_hash: [u8; 32],
+    pub entries: Vec<SnapshotEntry>,
+    pub signature: Signature,
+}
+
+impl Snapshot {
+    fn signing_payload(genesis_hash: &[u8; 32], entries: &[SnapshotEntry]) -> Result<[u8; 32]> {
+        let serialized_entries = bincode::serialize(entries)
+            .map_err(|e| anyhow!("Failed to serialize snapshot entries: {}", e))?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(genesis_hash);
+        hasher.update(serialized_entries);
+
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// Hash of the genesis balances, independent of the order of the entries and of
+/// how the addresses are written
+pub fn genesis_hash(genesis_args: &GenesisArgs) -> Result<[u8; 32]> {
+    let mut balances = genesis_args
+        .balances
+        .iter()
+        .map(|(address, amount)| Ok((Address::from_hex(address)?.as_hex(), *amount)))
+        .collect::<Result<Vec<_>>>()?;
+    balances.sort();
+
+    let mut hasher = Sha256::new();
+    for (address, amount) in balances {
+        hasher.update(address.as_bytes());
+        hasher.update(amount.to_be_bytes());
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Balance and selfchain height of an address
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SelfchainState {
+    pub balance: u64,
+    pub height: u32,
+}
+
+/// Start of a selfchain whose first records were pruned or loaded from a
+/// snapshot
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+struct Checkpoint {
+    state: SelfchainState,
+    /// Id of the last record covered by the checkpoint
+    head: TransactionHash,
+}
+
+/// Locks of a set of addresses. Dropped after their guards, it removes the
+/// locks no other caller holds, so the map doesn't grow with every address
+/// ever seen.
+pub(crate) struct AddressLocks<'a> {
+    manager: &'a TransactionManager,
+    addresses: Vec<Address>,
+    locks: Vec<Arc<Mutex<()>>>,
+}
+
+impl std::ops::Deref for AddressLocks<'_> {
+    type Target = [Arc<Mutex<()>>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.locks
+    }
+}
+
+impl Drop for AddressLocks<'_> {
+    fn drop(&mut self) {
+        // Locks are only cloned with the map locked, so a count of 1 means the
+        // map holds the last reference
+        let mut address_locks = self
+            .manager
+            .address_locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        self.locks.clear();
+        for address in &self.addresses {
+            if address_locks
+                .get(address)
+                .is_some_and(|lock| Arc::strong_count(lock) == 1)
+            {
+                address_locks.remove(address);
+            }
+        }
+    }
+}
+
 pub struct TransactionManager {
     pub lmdb_transaction_env: Arc<Environment>,
     pub db: Database,
+    refunds_db: Database,
+    checkpoints_db: Database,
+    // Serializes extensions of the same selfchain while letting different
+    // addresses proceed in parallel
+    address_locks: Mutex<HashMap<Address, Arc<Mutex<()>>>>,
 }
 
 impl TransactionManager {
-    pub fn new() -> Result<Self> {
-        let env = LMDB_ENV.clone();
+    /// Opens the LMDB environment stored in `path`, creating the directory if
+    /// needed. Each manager owns its environment, so several ledgers can live in
+    /// the same process.
+    pub fn new(path: &Path) -> Result<Self> {
+        std::fs::create_dir_all(path)
+            .map_err(|e| anyhow!("Failed to create {} directory: {}", path.display(), e))?;
+        let env = Arc::new(
+            lmdb::Environment::new()
+                .set_max_dbs(4)
+                .set_map_size(10 * 1024 * 1024)
+                .set_max_readers(126)
+                .open(path)
+                .map_err(|e| anyhow!("Failed to create LMDB environment: {}", e))?,
+        );
         let db = env.create_db(None, lmdb::DatabaseFlags::empty())?;
+        let meta_db = env.create_db(Some(META_DB_NAME), lmdb::DatabaseFlags::empty())?;
+        let refunds_db = env.create_db(Some(REFUNDS_DB_NAME), lmdb::DatabaseFlags::empty())?;
+        let checkpoints_db =
+            env.create_db(Some(CHECKPOINTS_DB_NAME), lmdb::DatabaseFlags::empty())?;
+        run_migrations(
+            &env,
+            &Databases {
+                main: db,
+                refunds: refunds_db,
+                checkpoints: checkpoints_db,
+            },
+            meta_db,
+        )?;
 
         Ok(TransactionManager {
             lmdb_transaction_env: env,
             db,
+            refunds_db,
+            checkpoints_db,
+            address_locks: Mutex::new(HashMap::new()),
         })
     }
 
+    /// Opens an existing database without writing to it, e.g. to snapshot the
+    /// database of a node. It is not migrated, so it must be at the current
+    /// schema version.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let env = Arc::new(
+            lmdb::Environment::new()
+                .set_flags(lmdb::EnvironmentFlags::READ_ONLY)
+                .set_max_dbs(4)
+                .set_max_readers(126)
+                .open(path)
+                .map_err(|e| anyhow!("Failed to open LMDB environment: {}", e))?,
+        );
+        let open_db = |name| {
+            env.open_db(name).map_err(|e| {
+                anyhow!(
+                    "Failed to open database {}, start the node once to migrate it: {}",
+                    name.unwrap_or("main"),
+                    e
+                )
+            })
+        };
+        let db = open_db(None)?;
+        let meta_db = open_db(Some(META_DB_NAME))?;
+        let refunds_db = open_db(Some(REFUNDS_DB_NAME))?;
+        let checkpoints_db = open_db(Some(CHECKPOINTS_DB_NAME))?;
+
+        let txn = env
+            .begin_ro_txn()
+            .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
+        let stored_version = stored_schema_version(&txn, meta_db)?;
+        drop(txn);
+        if stored_version != SCHEMA_VERSION {
+            return Err(anyhow!(
+                "Database schema version {} differs from the supported version {}, start the node once to migrate it",
+                stored_version,
+                SCHEMA_VERSION
+            ));
+        }
+
+        Ok(TransactionManager {
+            lmdb_transaction_env: env,
+            db,
+            refunds_db,
+            checkpoints_db,
+            address_locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Flushes the LMDB environment to disk
+    pub fn sync(&self) -> Result<()> {
+        self.lmdb_transaction_env
+            .sync(true)
+            .map_err(|e| anyhow!("Failed to sync database: {}", e))
+    }
+
+    /// Locks for the given addresses, deduplicated and ordered so that
+    /// concurrent callers always acquire them in the same order
+    pub(crate) fn address_locks(&self, addresses: &[Address]) -> AddressLocks<'_> {
+        let mut addresses = addresses.to_vec();
+        addresses.sort_by_key(|address| address.0);
+        addresses.dedup();
+
+        let mut address_locks = self.address_locks.lock().unwrap_or_else(|e| e.into_inner());
+        let locks = addresses
+            .iter()
+            .map(|address| Arc::clone(address_locks.entry(*address).or_default()))
+            .collect();
+
+        AddressLocks {
+            manager: self,
+            addresses,
+            locks,
+        }
+    }
+
     pub fn load_genesis_transactions(&self, genesis_args: GenesisArgs) -> Result<()> {
         // Begin a write transaction
         let mut txn = self
@@ -72,9 +518,16 @@ impl TransactionManager {
             let bytes = i.to_be_bytes();
             transaction_id[24..32].copy_from_slice(&bytes);
 
+            // Keyed by the normalized hex, as lookups are
+            let address = Address::from_hex(&address)?;
+            // Once pruned, the genesis record is covered by the checkpoint and
+            // must not come back on the next start
+            if self.checkpoint(&txn, address)?.state.height > 0 {
+                continue;
+            }
             let transaction = Transaction {
                 from: ZERO_ADDRESS,
-                to: Address::from_hex(&address)?,
+                to: address,
                 amount,
                 timestamp: 0,
             };
@@ -93,13 +546,13 @@ impl TransactionManager {
             // Use the transaction ID as the key
             txn.put(
                 self.db,
-                &format!("{}:0", &address),
+                &format!("{}:0", address.as_hex()),
                 &serialized_transaction_record,
                 lmdb::WriteFlags::empty(),
             )
             .map_err(|e| anyhow!("Failed to put transaction in database: {}", e))?;
 
-            info!("Added genesis balance for address: {}", &address);
+            info!("Added genesis balance for address: {}", address.as_hex());
         }
 
         // Commit the transaction
@@ -109,25 +562,142 @@ impl TransactionManager {
         Ok(())
     }
 
+    /// Hex of every address with a selfchain
+    fn addresses(&self) -> Result<BTreeSet<String>> {
+        let mut addresses = BTreeSet::new();
+
+        let reader = self
+            .lmdb_transaction_env
+            .begin_ro_txn()
+            .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
+        let mut cursor = reader
+            .open_ro_cursor(self.db)
+            .map_err(|e| anyhow!("Failed to create cursor: {}", e))?;
+
+        // Keys are formatted as `<address hex>:<selfchain height>`
+        for (key, _) in cursor.iter() {
+            let key =
+                std::str::from_utf8(key).map_err(|e| anyhow!("Invalid key in database: {}", e))?;
+            if matches!(key, META_DB_NAME | REFUNDS_DB_NAME | CHECKPOINTS_DB_NAME) {
+                continue;
+            }
+            let (address, _) = key
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid key in database: {}", key))?;
+            addresses.insert(address.to_string());
+        }
+
+        Ok(addresses)
+    }
+
+    /// Snapshot of the balance, height and head of every selfchain, signed with
+    /// `signing_key`
+    pub fn create_snapshot(
+        &self,
+        genesis_hash: [u8; 32],
+        signing_key: &SigningKey,
+    ) -> Result<Snapshot> {
+        let addresses = self.addresses()?;
+
+        // A single read transaction, so the entries are consistent with each other
+        let reader = self
+            .lmdb_transaction_env
+            .begin_ro_txn()
+            .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
+        let mut entries = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let address = Address::from_hex(&address)?;
+            let state = self.fold_selfchain(&reader, address, None)?;
+            entries.push(SnapshotEntry {
+                address,
+                balance: state.balance,
+                height: state.height,
+                head: self.head_at(&reader, address, state.height)?,
+            });
+        }
+
+        let payload = Snapshot::signing_payload(&genesis_hash, &entries)?;
+
+        Ok(Snapshot {
+            genesis_hash,
+            entries,
+            signature: signing_key.sign(&payload),
+        })
+    }
+
+    /// Loads a snapshot in place of the genesis transactions. Each entry becomes
+    /// the checkpoint of its selfchain, which is then extended from the snapshot
+    /// height. Selfchains already past the snapshot are left untouched, so the
+    /// snapshot can be loaded again on restart.
+    pub fn load_snapshot(
+        &self,
+        snapshot: Snapshot,
+        genesis_hash: [u8; 32],
+        trusted_key: VerifyingKey,
+    ) -> Result<()> {
+        if snapshot.genesis_hash != genesis_hash {
+            return Err(anyhow!("Snapshot was not produced from this genesis"));
+        }
+
+        let payload = Snapshot::signing_payload(&snapshot.genesis_hash, &snapshot.entries)?;
+        trusted_key
+            .verify_strict(&payload, &snapshot.signature)
+            .map_err(|e| anyhow!("Snapshot signature verification failed: {}", e))?;
+
+        let mut txn = self
+            .lmdb_transaction_env
+            .begin_rw_txn()
+            .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
+
+        for entry in snapshot.entries {
+            if self.fold_selfchain(&txn, entry.address, None)?.height >= entry.height {
+                continue;
+            }
+
+            let checkpoint = Checkpoint {
+                state: SelfchainState {
+                    balance: entry.balance,
+                    height: entry.height,
+                },
+                head: entry.head,
+            };
+            self.put_checkpoint(&mut txn, entry.address, &checkpoint)?;
+        }
+
+        txn.commit()
+            .map_err(|e| anyhow!("Failed to commit snapshot: {}", e))?;
+
+        info!("Loaded snapshot balances");
+
+        Ok(())
+    }
+
+    /// Appends the transaction to the selfchains of its sender and recipient and
+    /// returns its id. With `refund_of`, the transaction refunds the transaction
+    /// of that id: it must go from the original recipient back to the original
+    /// sender, and the refunds of a transaction can't add up to more than its
+    /// amount.
     pub fn add_transaction(
-        &mut self,
-        from: Address,
-        to: Address,
-        amount: u64,
+        &self,
+        transaction: Transaction,
         public_key: VerifyingKey,
-        timestamp: i64,
         signature: Signature,
+        refund_of: Option<&str>,
     ) -> Result<String> {
-        let transaction = Transaction {
-            from,
-            to,
-            amount,
-            timestamp,
-        };
+        let Transaction {
+            from, to, amount, ..
+        } = transaction;
 
-        if !Self::is_transaction_valid(transaction, public_key, signature)? {
+        if !Self::is_transaction_valid(transaction, public_key, signature, refund_of)? {
             return Err(anyhow!("Transaction is invalid"));
         }
+
+        let address_locks = self.address_locks(&[from, to]);
+        let _address_guards: Vec<_> = address_locks
+            .iter()
+            .map(|lock| lock.lock().unwrap_or_else(|e| e.into_inner()))
+            .collect();
+
         let (balance, selfchain_height_from) =
             self.get_address_balance_and_selfchain_height(from)?;
         let (_, selfchain_height_to) = self.get_address_balance_and_selfchain_height(to)?;
@@ -135,34 +705,65 @@ impl TransactionManager {
             return Err(anyhow!("Unsufficient balance"));
         }
 
+        self.write_transaction(
+            transaction,
+            refund_of,
+            selfchain_height_from,
+            selfchain_height_to,
+        )
+    }
+
+    /// Writes the transaction at the given heights of the sender and recipient
+    /// selfchains, failing with a conflict if either was extended since the
+    /// heights were read
+    fn write_transaction(
+        &self,
+        transaction: Transaction,
+        refund_of: Option<&str>,
+        selfchain_height_from: u32,
+        selfchain_height_to: u32,
+    ) -> Result<String> {
+        let Transaction { from, to, .. } = transaction;
+
         // write in the DB the transaction to both the recipient and the emitter
-        let serialized_tx = bincode::serialize(&transaction)
-            .map_err(|e| anyhow!("Failed to serialize transaction: {}", e))?;
+        let serialized_tx = bincode::serialize(&StoredTransaction {
+            transaction,
+            refund_of: refund_of.map(str::to_string),
+        })
+        .map_err(|e| anyhow!("Failed to serialize transaction: {}", e))?;
 
         let mut txn = self
             .lmdb_transaction_env
             .begin_rw_txn()
             .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
 
-        // We add the transaction to the sender personal chain
+        let transaction_id = format!("{}:{}", to.as_hex(), selfchain_height_to);
+
+        // Checked before the transaction is written, so a refund can't refer to
+        // itself
+        if let Some(original_id) = refund_of {
+            self.record_refund(&mut txn, original_id, &transaction, &transaction_id)?;
+        }
+
+        // We add the transaction to the sender personal chain. NO_OVERWRITE makes
+        // the write fail if another transaction already extended the chain at
+        // this height.
         txn.put(
             self.db,
             &format!("{}:{}", from.as_hex(), selfchain_height_from),
             &serialized_tx,
-            lmdb::WriteFlags::empty(),
+            lmdb::WriteFlags::NO_OVERWRITE,
         )
-        .map_err(|e| anyhow!("Failed to put transaction in database: {}", e))?;
-
-        let transaction_id = format!("{}:{}", to.as_hex(), selfchain_height_to);
+        .map_err(|e| Self::selfchain_put_error(e, from))?;
 
         // As well as the receiver personal chain
         txn.put(
             self.db,
             &transaction_id,
             &serialized_tx,
-            lmdb::WriteFlags::empty(),
+            lmdb::WriteFlags::NO_OVERWRITE,
         )
-        .map_err(|e| anyhow!("Failed to put transaction in database: {}", e))?;
+        .map_err(|e| Self::selfchain_put_error(e, to))?;
 
         txn.commit()?;
 
@@ -171,22 +772,147 @@ impl TransactionManager {
         Ok(transaction_id)
     }
 
-    pub fn get_address_balance_and_selfchain_height(
-        &mut self,
-        address: Address,
-    ) -> Result<(u64, u32)> {
-        let mut balance: u64 = 0;
+    /// Validates a transaction without writing it, against the stored state of
+    /// the sender or against `sender_override` when given. Returns the state the
+    /// sender selfchain would have after the transaction.
+    pub fn simulate_transaction(
+        &self,
+        transaction: Transaction,
+        public_key: VerifyingKey,
+        signature: Signature,
+        refund_of: Option<&str>,
+        sender_override: Option<SelfchainState>,
+    ) -> Result<SelfchainState> {
+        if !Self::is_transaction_valid(transaction, public_key, signature, refund_of)? {
+            return Err(anyhow!("Transaction is invalid"));
+        }
 
+        let sender = match sender_override {
+            Some(sender) => sender,
+            None => {
+                let (balance, height) =
+                    self.get_address_balance_and_selfchain_height(transaction.from)?;
+                SelfchainState { balance, height }
+            }
+        };
+        if sender.balance < transaction.amount {
+            return Err(anyhow!("Unsufficient balance"));
+        }
+
+        Ok(SelfchainState {
+            balance: sender.balance - transaction.amount,
+            height: sender.height + 1,
+        })
+    }
+
+    /// Checks the refund against the transaction it refunds and links them, in
+    /// the same write transaction as the refund itself. Refunds are sent by the
+    /// original recipient, whose address lock is held, so concurrent refunds of
+    /// the same transaction can't both pass the check.
+    fn record_refund(
+        &self,
+        txn: &mut RwTransaction,
+        original_id: &str,
+        refund: &Transaction,
+        refund_id: &str,
+    ) -> Result<()> {
+        let original: Transaction = match txn.get(self.db, &original_id) {
+            Ok(bytes) => bincode::deserialize(bytes)
+                .map_err(|e| anyhow!("Failed to deserialize transaction: {}", e))?,
+            Err(lmdb::Error::NotFound) => {
+                return Err(anyhow!("Refund of unknown transaction {}", original_id))
+            }
+            Err(e) => return Err(anyhow!("Failed to get transaction: {}", e)),
+        };
+        // A transaction is also stored in the selfchain of its sender. Only the id
+        // of the recipient's copy is accepted, so that refunds are tracked under a
+        // single key.
+        if !original_id.starts_with(&format!("{}:", original.to.as_hex())) {
+            return Err(anyhow!(
+                "{} is not a transaction id, use the id returned by submitTransaction",
+                original_id
+            ));
+        }
+
+        if original.from == ZERO_ADDRESS {
+            return Err(anyhow!(
+                "Genesis transaction {} can't be refunded",
+                original_id
+            ));
+        }
+        if refund.from != original.to || refund.to != original.from {
+            return Err(anyhow!(
+                "Refund of {} must go from its recipient back to its sender",
+                original_id
+            ));
+        }
+
+        let mut refund_record: RefundRecord = match txn.get(self.refunds_db, &original_id) {
+            Ok(bytes) => bincode::deserialize(bytes)
+                .map_err(|e| anyhow!("Failed to deserialize refund record: {}", e))?,
+            Err(lmdb::Error::NotFound) => RefundRecord::default(),
+            Err(e) => return Err(anyhow!("Failed to get refund record: {}", e)),
+        };
+
+        let refundable = original.amount - refund_record.refunded_amount;
+        if refund.amount > refundable {
+            return Err(anyhow!(
+                "Refund of {} exceeds the {} left to refund on {}",
+                refund.amount,
+                refundable,
+                original_id
+            ));
+        }
+
+        refund_record.refunded_amount += refund.amount;
+        refund_record.refund_ids.push(refund_id.to_string());
+        let serialized_record = bincode::serialize(&refund_record)
+            .map_err(|e| anyhow!("Failed to serialize refund record: {}", e))?;
+        txn.put(
+            self.refunds_db,
+            &original_id,
+            &serialized_record,
+            lmdb::WriteFlags::empty(),
+        )
+        .map_err(|e| anyhow!("Failed to put refund record in database: {}", e))?;
+
+        Ok(())
+    }
+
+    fn selfchain_put_error(error: lmdb::Error, address: Address) -> anyhow::Error {
+        match error {
+            lmdb::Error::KeyExist => anyhow!(
+                "Conflict: selfchain of {} was extended by another transaction",
+                address.as_hex()
+            ),
+            e => anyhow!("Failed to put transaction in database: {}", e),
+        }
+    }
+
+    pub fn get_address_balance_and_selfchain_height(&self, address: Address) -> Result<(u64, u32)> {
         let reader = self
             .lmdb_transaction_env
             .begin_ro_txn()
             .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
 
-        let mut iterator = 0;
+        let state = self.fold_selfchain(&reader, address, None)?;
+
+        Ok((state.balance, state.height))
+    }
+
+    /// Walks the selfchain of the address from its checkpoint, or from its start
+    /// if it was never pruned, up to `end` or to its head
+    fn fold_selfchain<T: LmdbTransaction>(
+        &self,
+        txn: &T,
+        address: Address,
+        end: Option<u32>,
+    ) -> Result<SelfchainState> {
+        let mut state = self.checkpoint(txn, address)?.state;
 
-        loop {
-            let key = format!("{}:{}", address.as_hex(), iterator);
-            let transaction_bytes = match reader.get(self.db, &key) {
+        while !matches!(end, Some(end) if state.height >= end) {
+            let key = format!("{}:{}", address.as_hex(), state.height);
+            let transaction_bytes = match txn.get(self.db, &key) {
                 Ok(bytes) => bytes,
                 Err(lmdb::Error::NotFound) => break,
                 Err(e) => return Err(anyhow!("Database error: {}", e)),
@@ -196,33 +922,178 @@ impl TransactionManager {
                 .map_err(|e| anyhow!("Failed to deserialize transaction: {}", e))?;
 
             if transaction.from == address {
-                if balance < transaction.amount {
+                if state.balance < transaction.amount {
                     return Err(anyhow!(
                         "Balance underflow detected for address: {}",
                         address.as_hex()
                     ));
                 }
-                balance -= transaction.amount;
+                state.balance -= transaction.amount;
             } else if transaction.to == address {
-                balance += transaction.amount;
+                state.balance += transaction.amount;
             } else {
                 return Err(anyhow!(
                     "Transaction {} does not have the address being checked as either sender or receiver",
                     key
                 ));
             }
-            iterator += 1;
+            state.height += 1;
         }
 
-        Ok((balance, iterator))
+        Ok(state)
+    }
+
+    /// Balance, height and head of the pruned start of the selfchain, zero when
+    /// nothing was pruned
+    fn checkpoint<T: LmdbTransaction>(&self, txn: &T, address: Address) -> Result<Checkpoint> {
+        match txn.get(self.checkpoints_db, &address.as_hex()) {
+            Ok(bytes) => bincode::deserialize(bytes)
+                .map_err(|e| anyhow!("Failed to deserialize checkpoint: {}", e)),
+            Err(lmdb::Error::NotFound) => Ok(Checkpoint::default()),
+            Err(e) => Err(anyhow!("Failed to get checkpoint: {}", e)),
+        }
+    }
+
+    fn put_checkpoint(
+        &self,
+        txn: &mut RwTransaction,
+        address: Address,
+        checkpoint: &Checkpoint,
+    ) -> Result<()> {
+        let serialized_checkpoint = bincode::serialize(checkpoint)
+            .map_err(|e| anyhow!("Failed to serialize checkpoint: {}", e))?;
+        txn.put(
+            self.checkpoints_db,
+            &address.as_hex(),
+            &serialized_checkpoint,
+            lmdb::WriteFlags::empty(),
+        )
+        .map_err(|e| anyhow!("Failed to put checkpoint in database: {}", e))
+    }
+
+    /// Id of the record of the selfchain just below `height`, read from the
+    /// checkpoint when that record was pruned or came from a snapshot. Zeroed at
+    /// height 0.
+    fn head_at<T: LmdbTransaction>(
+        &self,
+        txn: &T,
+        address: Address,
+        height: u32,
+    ) -> Result<TransactionHash> {
+        if height == 0 {
+            return Ok(TransactionHash::default());
+        }
+
+        let key = format!("{}:{}", address.as_hex(), height - 1);
+        match txn.get(self.db, &key) {
+            Ok(bytes) => {
+                let transaction: Transaction = bincode::deserialize(bytes)
+                    .map_err(|e| anyhow!("Failed to deserialize transaction: {}", e))?;
+                if transaction.from == ZERO_ADDRESS {
+                    return Ok(TransactionHash(transaction.calculate_id(None)?));
+                }
+
+                let stored_transaction: StoredTransaction = bincode::deserialize(bytes)
+                    .map_err(|e| anyhow!("Failed to deserialize transaction: {}", e))?;
+                Ok(TransactionHash(
+                    transaction.calculate_id(stored_transaction.refund_of.as_deref())?,
+                ))
+            }
+            Err(lmdb::Error::NotFound) => {
+                let checkpoint = self.checkpoint(txn, address)?;
+                if checkpoint.state.height != height {
+                    return Err(anyhow!("Transaction {} not found", key));
+                }
+                Ok(checkpoint.head)
+            }
+            Err(e) => Err(anyhow!("Database error: {}", e)),
+        }
+    }
+
+    /// Deletes the records of the selfchain older than its last `retain` ones,
+    /// folding them into its checkpoint. Heights are unchanged, so new
+    /// transactions keep extending the chain where they did. Returns the number
+    /// of records deleted.
+    pub fn prune_selfchain(&self, address: Address, retain: u32) -> Result<u32> {
+        // The head is needed to extend the chain
+        let retain = retain.max(1);
+
+        let address_locks = self.address_locks(&[address]);
+        let _address_guards: Vec<_> = address_locks
+            .iter()
+            .map(|lock| lock.lock().unwrap_or_else(|e| e.into_inner()))
+            .collect();
+
+        let mut txn = self
+            .lmdb_transaction_env
+            .begin_rw_txn()
+            .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
+
+        let checkpoint = self.checkpoint(&txn, address)?.state;
+        let head = self.fold_selfchain(&txn, address, None)?;
+        let pruned_height = head.height.saturating_sub(retain);
+        if pruned_height <= checkpoint.height {
+            return Ok(0);
+        }
+
+        // Read before the records it is hashed from are deleted
+        let new_checkpoint = Checkpoint {
+            state: self.fold_selfchain(&txn, address, Some(pruned_height))?,
+            head: self.head_at(&txn, address, pruned_height)?,
+        };
+        for height in checkpoint.height..pruned_height {
+            txn.del(self.db, &format!("{}:{}", address.as_hex(), height), None)
+                .map_err(|e| anyhow!("Failed to delete transaction: {}", e))?;
+        }
+
+        self.put_checkpoint(&mut txn, address, &new_checkpoint)?;
+        txn.commit()
+            .map_err(|e| anyhow!("Failed to commit pruning: {}", e))?;
+
+        Ok(pruned_height - checkpoint.height)
+    }
+
+    /// Prunes the selfchain of every address down to its last `retain` records
+    pub fn prune(&self, retain: u32) -> Result<u64> {
+        let mut pruned = 0;
+        for address in self.addresses()? {
+            pruned += self.prune_selfchain(Address::from_hex(&address)?, retain)? as u64;
+        }
+        Ok(pruned)
+    }
+
+    /// Current head of each address selfchain with its height. Addresses without
+    /// any transaction get a zeroed head and a height of 0. Heads are the ids signed
+    /// by the senders, so a refund head also commits to the id it refunds.
+    pub fn chain_heads(
+        &self,
+        addresses: &[Address],
+    ) -> Result<Vec<(Address, TransactionHash, u64)>> {
+        let mut chain_heads = Vec::with_capacity(addresses.len());
+
+        let reader = self
+            .lmdb_transaction_env
+            .begin_ro_txn()
+            .map_err(|e| anyhow!("Failed to begin transaction: {}", e))?;
+        for address in addresses {
+            let selfchain_height = self.fold_selfchain(&reader, *address, None)?.height;
+            chain_heads.push((
+                *address,
+                self.head_at(&reader, *address, selfchain_height)?,
+                selfchain_height as u64,
+            ));
+        }
+
+        Ok(chain_heads)
     }
 
     pub fn is_transaction_valid(
         transaction: Transaction,
         public_key: VerifyingKey,
         signature: Signature,
+        refund_of: Option<&str>,
     ) -> Result<bool> {
-        let transaction_id = transaction.calculate_id()?;
+        let transaction_id = transaction.calculate_id(refund_of)?;
 
         public_key
             .verify_strict(&transaction_id, &signature)
@@ -231,7 +1102,6 @@ impl TransactionManager {
         Ok(true)
     }
 
-    #[allow(dead_code)]
     pub fn get_transaction(&self, id: String) -> Result<Transaction> {
         let reader = self
             .lmdb_transaction_env
@@ -276,3 +1146,618 @@ impl TransactionManager {
         Ok(transaction_ids)
     }
 }
+
+/// Independent ledgers of the node, keyed by name. RPC requests pick one with
+/// their `ledger` field and fall back to [`DEFAULT_LEDGER`].
+#[derive(Default)]
+pub struct TransactionManagerRegistry {
+    managers: HashMap<String, Arc<TransactionManager>>,
+}
+
+impl TransactionManagerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(
+        &mut self,
+        name: &str,
+        manager: TransactionManager,
+    ) -> Result<Arc<TransactionManager>> {
+        if self.managers.contains_key(name) {
+            return Err(anyhow!("Ledger {} is already registered", name));
+        }
+
+        let manager = Arc::new(manager);
+        self.managers.insert(name.to_string(), Arc::clone(&manager));
+        Ok(manager)
+    }
+
+    pub fn sync_all(&self) -> Result<()> {
+        for manager in self.managers.values() {
+            manager.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Prunes every ledger, see [`TransactionManager::prune`]
+    pub fn prune_all(&self, retain: u32) -> Result<()> {
+        for (name, manager) in &self.managers {
+            let pruned = manager.prune(retain)?;
+            if pruned > 0 {
+                info!("Pruned {} transaction records from ledger {}", pruned, name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Result<Arc<TransactionManager>> {
+        let name = name.unwrap_or(DEFAULT_LEDGER);
+        self.managers
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown ledger: {}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genesis(balances: &[(&str, u64)]) -> GenesisArgs {
+        GenesisArgs {
+            balances: balances
+                .iter()
+                .map(|(address, amount)| (address.to_string(), *amount))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn genesis_address_with_0x_prefix_is_funded() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = TransactionManager::new(dir.path()).unwrap();
+        let address = [0xab; 32];
+
+        manager
+            .load_genesis_transactions(genesis(&[(&format!(" 0x{} ", hex::encode(address)), 1000)]))
+            .unwrap();
+
+        let (balance, height) = manager
+            .get_address_balance_and_selfchain_height(Address::new(address))
+            .unwrap();
+        assert_eq!((balance, height), (1000, 1));
+    }
+
+    const A: Address = Address([1; 32]);
+    const B: Address = Address([2; 32]);
+
+    fn funded_manager(dir: &Path) -> TransactionManager {
+        let manager = TransactionManager::new(dir).unwrap();
+        manager
+            .load_genesis_transactions(genesis(&[(&A.as_hex(), 1000)]))
+            .unwrap();
+        manager
+    }
+
+    fn transfer(
+        manager: &TransactionManager,
+        from: Address,
+        to: Address,
+        amount: u64,
+        refund_of: Option<&str>,
+    ) -> Result<String> {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let transaction = Transaction::new(from, to, amount)?;
+        let signature = signing_key.sign(&transaction.calculate_id(refund_of)?);
+        manager.add_transaction(
+            transaction,
+            signing_key.verifying_key(),
+            signature,
+            refund_of,
+        )
+    }
+
+    fn balance(manager: &TransactionManager, address: Address) -> u64 {
+        manager
+            .get_address_balance_and_selfchain_height(address)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn loaded_snapshot_matches_the_snapshotted_ledger() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+        transfer(&manager, A, B, 100, None).unwrap();
+        transfer(&manager, B, A, 30, None).unwrap();
+        manager.prune_selfchain(A, 1).unwrap();
+
+        let genesis_hash = genesis_hash(&genesis(&[(&A.as_hex(), 1000)])).unwrap();
+        let signing_key = SigningKey::from_bytes(&[9; 32]);
+        let snapshot = manager.create_snapshot(genesis_hash, &signing_key).unwrap();
+
+        let loaded_dir = tempfile::tempdir().unwrap();
+        let loaded = TransactionManager::new(loaded_dir.path()).unwrap();
+        loaded
+            .load_snapshot(snapshot.clone(), genesis_hash, signing_key.verifying_key())
+            .unwrap();
+
+        for address in [A, B] {
+            assert_eq!(
+                loaded
+                    .get_address_balance_and_selfchain_height(address)
+                    .unwrap(),
+                manager
+                    .get_address_balance_and_selfchain_height(address)
+                    .unwrap()
+            );
+        }
+        assert_eq!(
+            loaded.chain_heads(&[A, B]).unwrap(),
+            manager.chain_heads(&[A, B]).unwrap()
+        );
+
+        // Both ledgers extend the selfchains from the same height
+        transfer(&manager, A, B, 10, None).unwrap();
+        transfer(&loaded, A, B, 10, None).unwrap();
+        for address in [A, B] {
+            assert_eq!(
+                loaded
+                    .get_address_balance_and_selfchain_height(address)
+                    .unwrap(),
+                manager
+                    .get_address_balance_and_selfchain_height(address)
+                    .unwrap()
+            );
+        }
+
+        // Loading it again on restart doesn't rewind the selfchains
+        loaded
+            .load_snapshot(snapshot, genesis_hash, signing_key.verifying_key())
+            .unwrap();
+        assert_eq!(
+            loaded.get_address_balance_and_selfchain_height(A).unwrap(),
+            (920, 4)
+        );
+    }
+
+    #[test]
+    fn concurrent_transfers_from_the_same_address_extend_its_chain_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+        let barrier = std::sync::Barrier::new(2);
+
+        // Both transfers read the heights before either is written, as writers
+        // not sharing the address locks would, e.g. another process
+        let results: Vec<Result<String>> = std::thread::scope(|scope| {
+            let transfers: Vec<_> = [B, Address([3; 32])]
+                .into_iter()
+                .map(|to| {
+                    let (manager, barrier) = (&manager, &barrier);
+                    scope.spawn(move || {
+                        let height = |address| {
+                            manager
+                                .get_address_balance_and_selfchain_height(address)
+                                .unwrap()
+                                .1
+                        };
+                        let (height_from, height_to) = (height(A), height(to));
+                        barrier.wait();
+                        let transaction = Transaction {
+                            from: A,
+                            to,
+                            amount: 1000,
+                            timestamp: 0,
+                        };
+                        manager.write_transaction(transaction, None, height_from, height_to)
+                    })
+                })
+                .collect();
+            transfers
+                .into_iter()
+                .map(|transfer| transfer.join().unwrap())
+                .collect()
+        });
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        let error = results.into_iter().find_map(Result::err).unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Conflict: selfchain of {} was extended by another transaction",
+                A.as_hex()
+            )
+        );
+        assert_eq!(
+            manager.get_address_balance_and_selfchain_height(A).unwrap(),
+            (0, 2)
+        );
+    }
+
+    #[test]
+    fn address_locks_are_removed_once_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+
+        let held = manager.address_locks(&[A]);
+        transfer(&manager, A, B, 10, None).unwrap();
+        // Still held here, so only the lock of B is gone
+        assert_eq!(
+            manager
+                .address_locks
+                .lock()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&A]
+        );
+
+        drop(held);
+        assert!(manager.address_locks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn chain_heads_follow_selfchains_of_different_lengths() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+        let unknown = Address([3; 32]);
+        transfer(&manager, A, B, 10, None).unwrap();
+        let last_id = transfer(&manager, A, B, 20, None).unwrap();
+
+        let last = manager.get_transaction(last_id).unwrap();
+        let head = TransactionHash(last.calculate_id(None).unwrap());
+        assert_eq!(
+            manager.chain_heads(&[A, B, unknown]).unwrap(),
+            vec![
+                (A, head, 3),
+                (B, head, 2),
+                (unknown, TransactionHash::default(), 0)
+            ]
+        );
+
+        // Only the chains the new transaction extends move
+        transfer(&manager, B, unknown, 5, None).unwrap();
+        let heads = manager.chain_heads(&[A, B, unknown]).unwrap();
+        assert_eq!(heads[0], (A, head, 3));
+        assert_eq!(heads[1].2, 3);
+        assert_eq!(heads[2].2, 1);
+        assert_eq!(heads[1].1, heads[2].1);
+        assert_ne!(heads[1].1, head);
+    }
+
+    fn simulate(
+        manager: &TransactionManager,
+        amount: u64,
+        sender_override: Option<SelfchainState>,
+    ) -> Result<SelfchainState> {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let transaction = Transaction::new(A, B, amount)?;
+        let signature = signing_key.sign(&transaction.calculate_id(None)?);
+        manager.simulate_transaction(
+            transaction,
+            signing_key.verifying_key(),
+            signature,
+            None,
+            sender_override,
+        )
+    }
+
+    #[test]
+    fn simulation_runs_against_the_override_instead_of_the_stored_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+        let hypothetical = SelfchainState {
+            balance: 50,
+            height: 7,
+        };
+
+        assert_eq!(
+            simulate(&manager, 40, Some(hypothetical)).unwrap(),
+            SelfchainState {
+                balance: 10,
+                height: 8
+            }
+        );
+        // Covered by the stored balance, but not by the override
+        let error = simulate(&manager, 100, Some(hypothetical)).unwrap_err();
+        assert!(error.to_string().contains("Unsufficient balance"));
+        assert_eq!(
+            simulate(&manager, 100, None).unwrap(),
+            SelfchainState {
+                balance: 900,
+                height: 2
+            }
+        );
+
+        // Nothing is written
+        assert_eq!(
+            manager.get_address_balance_and_selfchain_height(A).unwrap(),
+            (1000, 1)
+        );
+        assert_eq!(balance(&manager, B), 0);
+    }
+
+    /// Writes `version` as the stored schema version, or removes it
+    fn set_schema_version(manager: &TransactionManager, version: Option<u32>) {
+        let env = &manager.lmdb_transaction_env;
+        let meta_db = env.open_db(Some(META_DB_NAME)).unwrap();
+        let mut txn = env.begin_rw_txn().unwrap();
+        match version {
+            Some(version) => txn
+                .put(
+                    meta_db,
+                    &SCHEMA_VERSION_KEY,
+                    &version.to_be_bytes(),
+                    lmdb::WriteFlags::empty(),
+                )
+                .unwrap(),
+            None => txn.del(meta_db, &SCHEMA_VERSION_KEY, None).unwrap(),
+        }
+        txn.commit().unwrap();
+    }
+
+    fn schema_version(manager: &TransactionManager) -> u32 {
+        let env = &manager.lmdb_transaction_env;
+        let meta_db = env.open_db(Some(META_DB_NAME)).unwrap();
+        let txn = env.begin_ro_txn().unwrap();
+        u32::from_be_bytes(
+            txn.get(meta_db, &SCHEMA_VERSION_KEY)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn older_database_is_migrated() {
+        for old_version in [None, Some(3)] {
+            let dir = tempfile::tempdir().unwrap();
+            {
+                let manager = TransactionManager::new(dir.path()).unwrap();
+                // Checkpoints held only the balance and height before version 4
+                let old_checkpoint = SelfchainState {
+                    balance: 70,
+                    height: 3,
+                };
+                let mut txn = manager.lmdb_transaction_env.begin_rw_txn().unwrap();
+                txn.put(
+                    manager.checkpoints_db,
+                    &A.as_hex(),
+                    &bincode::serialize(&old_checkpoint).unwrap(),
+                    lmdb::WriteFlags::empty(),
+                )
+                .unwrap();
+                txn.commit().unwrap();
+                set_schema_version(&manager, old_version);
+            }
+
+            let manager = TransactionManager::new(dir.path()).unwrap();
+            assert_eq!(schema_version(&manager), SCHEMA_VERSION);
+            assert_eq!(
+                manager.get_address_balance_and_selfchain_height(A).unwrap(),
+                (70, 3)
+            );
+            transfer(&manager, A, B, 20, None).unwrap();
+            assert_eq!(
+                manager.get_address_balance_and_selfchain_height(A).unwrap(),
+                (50, 4)
+            );
+        }
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let manager = TransactionManager::new(dir.path()).unwrap();
+            set_schema_version(&manager, Some(SCHEMA_VERSION + 1));
+        }
+
+        let error = TransactionManager::new(dir.path()).err().unwrap();
+        assert!(
+            error
+                .to_string()
+                .contains("is newer than the supported version"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn read_only_database_is_not_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let manager = funded_manager(dir.path());
+            transfer(&manager, A, B, 100, None).unwrap();
+        }
+
+        let manager = TransactionManager::open_read_only(dir.path()).unwrap();
+        assert_eq!(
+            manager.get_address_balance_and_selfchain_height(B).unwrap(),
+            (100, 1)
+        );
+        drop(manager);
+
+        set_schema_version(&TransactionManager::new(dir.path()).unwrap(), Some(3));
+        let error = TransactionManager::open_read_only(dir.path())
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("start the node once to migrate it"));
+    }
+
+    #[test]
+    fn genesis_hash_ignores_how_addresses_are_written() {
+        let hash = genesis_hash(&genesis(&[(&A.as_hex(), 1000)])).unwrap();
+        let prefixed = format!("0x{}", A.as_hex().to_uppercase());
+        assert_eq!(genesis_hash(&genesis(&[(&prefixed, 1000)])).unwrap(), hash);
+        assert!(genesis_hash(&genesis(&[("not hex", 1000)])).is_err());
+    }
+
+    #[test]
+    fn pruning_keeps_balances_heights_and_heads() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+        let c = Address([3; 32]);
+        for amount in 1..=5 {
+            transfer(&manager, A, B, amount * 10, None).unwrap();
+        }
+        transfer(&manager, B, c, 25, None).unwrap();
+
+        let addresses = [A, B, c];
+        let state = |manager: &TransactionManager| {
+            addresses
+                .iter()
+                .map(|address| {
+                    manager
+                        .get_address_balance_and_selfchain_height(*address)
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        let (before, heads_before) = (state(&manager), manager.chain_heads(&addresses).unwrap());
+
+        // A keeps 2 of its 6 records and B 2 of its 6, c has a single one
+        assert_eq!(manager.prune(2).unwrap(), 8);
+        assert_eq!(manager.prune(2).unwrap(), 0);
+        assert!(manager
+            .get_transaction(format!("{}:0", A.as_hex()))
+            .is_err());
+
+        // Genesis is loaded again on every start
+        drop(manager);
+        let manager = funded_manager(dir.path());
+        assert!(manager
+            .get_transaction(format!("{}:0", A.as_hex()))
+            .is_err());
+
+        assert_eq!(state(&manager), before);
+        assert_eq!(manager.chain_heads(&addresses).unwrap(), heads_before);
+
+        // Chains are extended from where they were
+        transfer(&manager, A, c, 100, None).unwrap();
+        assert_eq!(
+            manager.get_address_balance_and_selfchain_height(A).unwrap(),
+            (750, 7)
+        );
+        assert_eq!(
+            manager.get_address_balance_and_selfchain_height(c).unwrap(),
+            (125, 2)
+        );
+    }
+
+    /// Transfer from A to B refunded once, with the signed id of the refund
+    fn refunded_transfer(manager: &TransactionManager) -> (String, TransactionHash) {
+        let original_id = transfer(manager, A, B, 100, None).unwrap();
+        let refund_id = transfer(manager, B, A, 40, Some(&original_id)).unwrap();
+        let refund = manager.get_transaction(refund_id).unwrap();
+        let signed_id = TransactionHash(refund.calculate_id(Some(&original_id)).unwrap());
+        (original_id, signed_id)
+    }
+
+    #[test]
+    fn refund_head_is_its_signed_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+
+        let (_, signed_id) = refunded_transfer(&manager);
+
+        assert_eq!(
+            manager.chain_heads(&[A, B]).unwrap(),
+            vec![(A, signed_id, 3), (B, signed_id, 2)]
+        );
+    }
+
+    #[test]
+    fn refund_links_are_recovered_by_the_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let signed_id = {
+            let manager = funded_manager(dir.path());
+            let (_, signed_id) = refunded_transfer(&manager);
+
+            // Before version 5, records only held the transfer
+            let mut txn = manager.lmdb_transaction_env.begin_rw_txn().unwrap();
+            for key in [format!("{}:2", A.as_hex()), format!("{}:1", B.as_hex())] {
+                let transaction: Transaction =
+                    bincode::deserialize(txn.get(manager.db, &key).unwrap()).unwrap();
+                txn.put(
+                    manager.db,
+                    &key,
+                    &bincode::serialize(&transaction).unwrap(),
+                    lmdb::WriteFlags::empty(),
+                )
+                .unwrap();
+            }
+            txn.commit().unwrap();
+            set_schema_version(&manager, Some(4));
+            signed_id
+        };
+
+        let manager = TransactionManager::new(dir.path()).unwrap();
+        assert_eq!(
+            manager.chain_heads(&[A, B]).unwrap(),
+            vec![(A, signed_id, 3), (B, signed_id, 2)]
+        );
+    }
+
+    #[test]
+    fn refund_returns_funds_to_the_original_sender() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+
+        let original_id = transfer(&manager, A, B, 100, None).unwrap();
+        transfer(&manager, B, A, 60, Some(&original_id)).unwrap();
+        transfer(&manager, B, A, 40, Some(&original_id)).unwrap();
+
+        assert_eq!(balance(&manager, A), 1000);
+        assert_eq!(balance(&manager, B), 0);
+    }
+
+    #[test]
+    fn refund_over_the_original_amount_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+
+        let original_id = transfer(&manager, A, B, 100, None).unwrap();
+        transfer(&manager, A, B, 100, None).unwrap();
+        transfer(&manager, B, A, 60, Some(&original_id)).unwrap();
+
+        let error = transfer(&manager, B, A, 41, Some(&original_id)).unwrap_err();
+        assert!(error.to_string().contains("exceeds the 40 left to refund"));
+        assert_eq!(balance(&manager, B), 140);
+    }
+
+    #[test]
+    fn refund_of_unknown_transaction_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+        transfer(&manager, A, B, 100, None).unwrap();
+
+        let unknown_id = format!("{}:5", B.as_hex());
+        let error = transfer(&manager, B, A, 10, Some(&unknown_id)).unwrap_err();
+        assert!(error.to_string().contains("Refund of unknown transaction"));
+    }
+
+    #[test]
+    fn refund_link_is_covered_by_the_signature() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = funded_manager(dir.path());
+        let original_id = transfer(&manager, A, B, 100, None).unwrap();
+
+        // Signed as a plain transfer, relayed as a refund
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let transaction = Transaction::new(B, A, 10).unwrap();
+        let signature = signing_key.sign(&transaction.calculate_id(None).unwrap());
+        let result = manager.add_transaction(
+            transaction,
+            signing_key.verifying_key(),
+            signature,
+            Some(&original_id),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(balance(&manager, B), 100);
+    }
+}